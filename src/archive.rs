@@ -0,0 +1,210 @@
+//! Read subtitle packs straight out of .zip and .tar(.gz) archives.
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use flate2::read::GzDecoder;
+
+/// Largest member we'll read. Subtitles are far smaller, so anything bigger is a broken archive or
+/// not worth holding in memory.
+const MAX_MEMBER_SIZE: u64 = 64 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// A regular file stored in an archive.
+pub struct ArchiveMember {
+    /// Path of the file within the archive.
+    pub name: PathBuf,
+    pub data: Vec<u8>,
+}
+
+/// Reads every regular file in the archive for which `wanted` returns true.
+/// Members are read up front, since tarballs can't be seeked back into to fetch them later.
+pub fn read_members(
+    path: &Path,
+    kind: ArchiveKind,
+    mut wanted: impl FnMut(&Path) -> bool,
+) -> anyhow::Result<Vec<ArchiveMember>> {
+    let file = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?,
+    );
+    match kind {
+        ArchiveKind::Zip => read_zip_members(file, &mut wanted),
+        ArchiveKind::Tar => read_tar_members(file, &mut wanted),
+        ArchiveKind::TarGz => read_tar_members(GzDecoder::new(file), &mut wanted),
+    }
+    .with_context(|| format!("Failed to read archive '{}'", path.display()))
+}
+
+fn read_zip_members(
+    file: BufReader<File>,
+    wanted: &mut impl FnMut(&Path) -> bool,
+) -> anyhow::Result<Vec<ArchiveMember>> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    let mut members = vec![];
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        // entries with absolute or `..` paths have no sensible name, so skip them; `enclosed_name`
+        // only catches the latter, absolute ones it makes relative
+        if entry.name().is_ok_and(|name| name.starts_with(['/', '\\'])) {
+            continue;
+        }
+        let Some(name) = entry.enclosed_name() else {
+            continue;
+        };
+        if entry.is_file() && wanted(&name) {
+            let size = entry.size();
+            let data = read_member(&mut entry, &name, size)?;
+            members.push(ArchiveMember { name, data });
+        }
+    }
+    Ok(members)
+}
+
+fn read_tar_members(
+    reader: impl Read,
+    wanted: &mut impl FnMut(&Path) -> bool,
+) -> anyhow::Result<Vec<ArchiveMember>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut members = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.into_owned();
+        if wanted(&name) {
+            let size = entry.size();
+            let data = read_member(&mut entry, &name, size)?;
+            members.push(ArchiveMember { name, data });
+        }
+    }
+    Ok(members)
+}
+
+/// Reads a member, going by the size its header declares only as far as `MAX_MEMBER_SIZE`, since
+/// the header may well be lying.
+fn read_member(entry: impl Read, name: &Path, declared_size: u64) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(declared_size.min(MAX_MEMBER_SIZE) as usize);
+    entry.take(MAX_MEMBER_SIZE + 1).read_to_end(&mut data)?;
+    anyhow::ensure!(
+        data.len() as u64 <= MAX_MEMBER_SIZE,
+        "'{}' is larger than {} MiB",
+        name.display(),
+        MAX_MEMBER_SIZE >> 20
+    );
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("omoide-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn names(members: &[ArchiveMember]) -> Vec<&Path> {
+        members.iter().map(|member| member.name.as_path()).collect()
+    }
+
+    fn write_tar(writer: impl Write, files: &[(&str, &str)]) {
+        let mut builder = tar::Builder::new(writer);
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap();
+    }
+
+    #[test]
+    fn reads_wanted_zip_members() {
+        let dir = test_dir("archive-zip");
+        let path = dir.join("subs.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        for name in [
+            "Show/01.srt",
+            "Show/notes.txt",
+            "../escaped.srt",
+            "/absolute.srt",
+        ] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all("1\n00:00:01,000 --> 00:00:02,000\nこんにちは\n".as_bytes())
+                .unwrap();
+        }
+        zip.add_directory("Show/Extras/", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.finish().unwrap();
+
+        let members = read_members(&path, ArchiveKind::Zip, |name| {
+            name.extension().is_some_and(|ext| ext == "srt")
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        let members = members.unwrap();
+        assert_eq!(names(&members), [Path::new("Show/01.srt")]);
+        assert!(members[0].data.starts_with(b"1\n00:00:01,000"));
+    }
+
+    #[test]
+    fn reads_wanted_tar_members() {
+        let dir = test_dir("archive-tar");
+        let files = [("Show/01.srt", "first"), ("Show/02.ass", "second")];
+        let tar_path = dir.join("subs.tar");
+        write_tar(File::create(&tar_path).unwrap(), &files);
+        let gz_path = dir.join("subs.tar.gz");
+        let gz = GzEncoder::new(File::create(&gz_path).unwrap(), Compression::default());
+        write_tar(gz, &files);
+
+        let tar = read_members(&tar_path, ArchiveKind::Tar, |name| name.ends_with("01.srt"));
+        let gz = read_members(&gz_path, ArchiveKind::TarGz, |_| true);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let tar = tar.unwrap();
+        assert_eq!(names(&tar), [Path::new("Show/01.srt")]);
+        assert_eq!(tar[0].data, b"first");
+        let gz = gz.unwrap();
+        assert_eq!(
+            names(&gz),
+            [Path::new("Show/01.srt"), Path::new("Show/02.ass")]
+        );
+        assert_eq!(gz[1].data, b"second");
+    }
+
+    #[test]
+    fn does_not_trust_declared_sizes() {
+        let data = read_member(&b"tiny"[..], Path::new("a.srt"), u64::MAX).unwrap();
+        assert_eq!(data, b"tiny");
+        let huge = std::io::repeat(b'a').take(MAX_MEMBER_SIZE + 1);
+        assert!(read_member(huge, Path::new("a.srt"), 0).is_err());
+    }
+}
@@ -2,10 +2,16 @@ use std::path::PathBuf;
 
 use clap::{Args, Parser, Subcommand};
 
+use crate::{dedup::ShingleUnit, furigana::FuriganaFormat, index::TermKind, subs::SubtitleFormat};
+
 #[derive(Clone, Debug, Parser)]
 pub struct Cli {
     #[command(subcommand)]
     pub cmd: Option<Commands>,
+    /// Directory with the JMdict and KANJIDIC data, by default $OMOIDE_DATA_DIR or
+    /// ~/.local/share/omoide
+    #[clap(long, global = true)]
+    pub data_dir: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug, Subcommand)]
@@ -20,8 +26,14 @@ pub enum Commands {
     Analyze(AnalysisArgs),
     /// Find example sentences using a given word
     Examples(ExampleArgs),
-    /// Annotate a sentence with furigana (outputs ruby)
+    /// Find subtitle lines matching a token pattern, e.g. '[lemma="て"] [lemma="しまう"]'
+    Query(QueryArgs),
+    /// Report groups of near-duplicate subtitle files in the corpus
+    Dedup(DuplicatesArgs),
+    /// Annotate a sentence with furigana (outputs ruby, or another format with --format)
     Furigana(FuriganaArgs),
+    /// Look up kanji: meanings, readings, stroke count, grade and words using them
+    Kanji(KanjiArgs),
 }
 
 #[derive(Clone, Debug, Args)]
@@ -29,6 +41,81 @@ pub struct ManageArgs {
     /// Download a bunch of data to form our own corpus for practicing against
     #[clap(long)]
     pub download: bool,
+    /// Clear out analyses cached by other versions of the NLP engine from this cache directory
+    #[clap(long)]
+    pub prune_cache: Option<PathBuf>,
+    /// Import a JMdict XML release (e.g. JMdict_e.xml) into the data directory
+    #[clap(long, value_name = "PATH")]
+    pub import_jmdict: Option<PathBuf>,
+    /// Import a KANJIDIC2 XML release (kanjidic2.xml) into the data directory
+    #[clap(long, value_name = "PATH")]
+    pub import_kanjidic: Option<PathBuf>,
+    /// Prebuild the dictionaries into the data directory so they load quickly. Done after
+    /// importing anyway
+    #[clap(long)]
+    pub build_index: bool,
+    /// Check that the imported dictionaries load in full
+    #[clap(long)]
+    pub verify_data: bool,
+    /// Show which dictionaries are imported, with their versions and dates
+    #[clap(long)]
+    pub data_info: bool,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct CorpusArgs {
+    /// Directory with subtitle files
+    #[clap(long, short = 'd')]
+    pub subtitles_dir: PathBuf,
+    /// Also look for subtitles in subdirectories, one show per folder
+    #[clap(long, short)]
+    pub recursive: bool,
+    /// Only read files matching these glob patterns, relative to the subtitles directory
+    #[clap(long)]
+    pub include: Vec<String>,
+    /// Skip files and folders matching these glob patterns, relative to the subtitles directory
+    #[clap(long)]
+    pub exclude: Vec<String>,
+    /// Descend into symlinked folders
+    #[clap(long)]
+    pub follow_symlinks: bool,
+    /// Where to keep NLP results between runs
+    #[clap(long, default_value = ".omoide_cache")]
+    pub cache_dir: PathBuf,
+    /// Don't read or write the analysis cache
+    #[clap(long)]
+    pub no_cache: bool,
+    #[command(flatten)]
+    pub dedup: DedupArgs,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct DedupArgs {
+    /// Compare files for duplicates by runs of tokens, or of characters, which doesn't need
+    /// the tokenizer
+    #[clap(long, value_enum, default_value = "tokens")]
+    pub shingle: ShingleUnit,
+    /// Length of the runs compared, defaults to 3 tokens or 5 characters
+    #[clap(long)]
+    pub shingle_size: Option<usize>,
+    /// Number of hashes in each file's MinHash signature
+    #[clap(long, default_value_t = 200)]
+    pub num_hashes: usize,
+    /// Hashes per LSH band, smaller bands catch less similar files but compare more pairs
+    #[clap(long, default_value_t = 4)]
+    pub band_size: usize,
+    /// Estimated Jaccard similarity above which files count as duplicates
+    #[clap(long, default_value_t = 0.8)]
+    pub dedup_threshold: f32,
+    /// Among duplicates, keep the file read first rather than the best one
+    #[clap(long)]
+    pub keep_first: bool,
+    /// Among duplicates, keep releases from these groups, most preferred first
+    #[clap(long)]
+    pub prefer_group: Vec<String>,
+    /// Among duplicates, keep files in these formats, most preferred first
+    #[clap(long, value_enum)]
+    pub prefer_format: Vec<SubtitleFormat>,
 }
 
 #[derive(Clone, Debug, Args)]
@@ -36,9 +123,20 @@ pub struct StatsArgs {
     /// Generate statistics for the words present in the subtitles
     #[clap(long)]
     pub word_stats: bool,
-    /// Directory with subtitle files
-    #[clap(long, short = 'd')]
-    pub subtitles_dir: PathBuf,
+    /// Also list the most frequent words for each show separately
+    #[clap(long)]
+    pub by_show: bool,
+    /// List every kanji in the subtitles, most frequent first, with how much of the text they
+    /// cover between them
+    #[clap(long)]
+    pub kanji_coverage: bool,
+    /// File listing words you already know, one per line, to mark the kanji you know
+    #[clap(long)]
+    pub known_words: Option<PathBuf>,
+    #[command(flatten)]
+    pub corpus: CorpusArgs,
+    #[command(flatten)]
+    pub repeats: RepeatArgs,
 }
 
 #[derive(Clone, Debug, Args)]
@@ -46,19 +144,115 @@ pub struct ExampleArgs {
     /// Word to find example usage of in subs
     #[clap(long, short)]
     pub word: String,
-    /// Directory with subtitle files
-    #[clap(long, short = 'd')]
-    pub subtitles_dir: PathBuf,
+    /// Which form of the words in the subs to match against
+    #[clap(long, value_enum, default_value = "lemma")]
+    pub match_on: TermKind,
+    #[command(flatten)]
+    pub corpus: CorpusArgs,
     /// Limit the maximum number of retrieved examples
     #[clap(long)]
     pub max: Option<usize>,
+    #[command(flatten)]
+    pub concordance: ConcordanceArgs,
+    #[command(flatten)]
+    pub ranking: RankingArgs,
+    #[command(flatten)]
+    pub repeats: RepeatArgs,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct QueryArgs {
+    /// Sequence of token patterns, each like [lemma="かける"], [pos=NOUN] or [role=verb & rel=obj]
+    pub pattern: String,
+    #[command(flatten)]
+    pub corpus: CorpusArgs,
+    /// Limit the maximum number of retrieved lines
+    #[clap(long)]
+    pub max: Option<usize>,
+    #[command(flatten)]
+    pub concordance: ConcordanceArgs,
+    #[command(flatten)]
+    pub ranking: RankingArgs,
+    #[command(flatten)]
+    pub repeats: RepeatArgs,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct ConcordanceArgs {
+    /// Also show this many subtitle lines before and after each match
+    #[clap(long, short = 'C', default_value_t = 0)]
+    pub context: usize,
+    /// How much of the line to show on either side of the match, in columns
+    #[clap(long, default_value_t = 30)]
+    pub width: usize,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct RankingArgs {
+    /// File listing words you already know, one per line, so examples using them rank higher
+    #[clap(long)]
+    pub known_words: Option<PathBuf>,
+    /// List matches in episode order instead of ranking them
+    #[clap(long)]
+    pub in_order: bool,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct RepeatArgs {
+    /// Leave out lines repeated across episodes of a show, like song lyrics and previews
+    #[clap(long)]
+    pub exclude_repeated: bool,
+    /// Proportion of a show's episodes a line has to be in, in about the same place, to count
+    /// as repeated
+    #[clap(long, default_value_t = 0.5)]
+    pub repeated_in: f32,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct DuplicatesArgs {
+    #[command(flatten)]
+    pub corpus: CorpusArgs,
 }
 
 #[derive(Clone, Debug, Args)]
 pub struct FuriganaArgs {
-    /// Input sentence(s) to annotate with furigana
+    /// Input sentence(s) to annotate with furigana, taken together as one document
     #[clap(long, short)]
     pub sentence: Vec<String>,
+    /// How to write the furigana
+    #[clap(long, value_enum, default_value = "html")]
+    pub format: FuriganaFormat,
+    /// Flag readings guessed from the kanji of words that aren't in the dictionary, with a
+    /// question mark after them or a "guessed" class in HTML
+    #[clap(long)]
+    pub mark_guessed: bool,
+    /// File listing words you already know, one per line, to leave without furigana
+    #[clap(long)]
+    pub known_words: Option<PathBuf>,
+    /// Only give furigana for kanji taught after this school grade: 1 to 6 for primary school,
+    /// 8 for the rest of the jōyō kanji, 9 and 10 for kanji used in names
+    #[clap(long, value_name = "GRADE")]
+    pub above_grade: Option<u8>,
+    /// Only give furigana for kanji harder than this level of the old JLPT, from 4 (easiest)
+    /// to 1
+    #[clap(long, value_name = "LEVEL")]
+    pub above_jlpt: Option<u8>,
+    /// Only give furigana the first time each word comes up
+    #[clap(long)]
+    pub first_only: bool,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct KanjiArgs {
+    /// Kanji to look up, anything else in the text is ignored
+    pub kanji: String,
+    /// Where the corpus index was saved, to list words from the corpus using the kanji. It holds the
+    /// corpus as of the last `examples` or `query` run
+    #[clap(long, default_value = ".omoide_cache")]
+    pub cache_dir: PathBuf,
+    /// How many words from the corpus to list
+    #[clap(long, default_value_t = 10)]
+    pub words: usize,
 }
 
 #[derive(Clone, Debug, Args)]
@@ -0,0 +1,123 @@
+//! On-disk cache of NLP results, so re-running over the same corpus doesn't have to go through
+//! spaCy again.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    document::Document,
+    nlp::{Analysis, DocumentTokenization, Engine},
+};
+
+/// Bump whenever the serialized types change shape, or the way we derive them does.
+const CACHE_FORMAT_VERSION: u32 = 2;
+
+/// Holds the version of the engine the cache was last opened with.
+const ENGINE_FILE: &str = "engine.txt";
+
+#[derive(Deserialize)]
+struct CacheEntry {
+    engine: String,
+    tokenization: Option<DocumentTokenization>,
+    analysis: Option<Vec<Analysis>>,
+}
+
+/// Same layout as `CacheEntry`, borrowing from the document so we don't clone to serialize it.
+#[derive(Serialize)]
+struct CacheEntryRef<'a> {
+    engine: &'a str,
+    tokenization: Option<&'a DocumentTokenization>,
+    analysis: Option<&'a [Analysis]>,
+}
+
+/// Stores the tokenization and analysis of documents, keyed by a hash of their contents and the
+/// engine that produced them. Entries from other engine versions are ignored, and can be cleared
+/// out with `prune`.
+pub struct AnalysisCache {
+    dir: PathBuf,
+    engine: String,
+    engine_hash: u64,
+}
+
+impl AnalysisCache {
+    pub fn open(dir: impl Into<PathBuf>, engine: &Engine) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create cache directory '{}'", dir.display()))?;
+        let engine_path = dir.join(ENGINE_FILE);
+        fs::write(&engine_path, engine.version())
+            .with_context(|| format!("Couldn't write '{}'", engine_path.display()))?;
+        let engine = format!("{}/cache-v{}", engine.version(), CACHE_FORMAT_VERSION);
+        Ok(Self {
+            dir,
+            engine_hash: fxhash::hash64(&engine),
+            engine,
+        })
+    }
+
+    /// Version of the engine the cache in `dir` was last opened with, so runs that don't start the
+    /// engine can still tell whether what they kept from earlier runs is outdated.
+    pub fn last_engine(dir: &Path) -> Option<String> {
+        fs::read_to_string(dir.join(ENGINE_FILE)).ok()
+    }
+
+    fn entry_path(&self, doc: &Document) -> PathBuf {
+        self.dir.join(format!(
+            "{:016x}-{:016x}.bin",
+            doc.content_hash(),
+            self.engine_hash
+        ))
+    }
+
+    fn load(&self, path: &Path) -> Option<CacheEntry> {
+        let bytes = fs::read(path).ok()?;
+        // a corrupt entry is just a cache miss, it'll get overwritten
+        let entry: CacheEntry = bincode::deserialize(&bytes).ok()?;
+        (entry.engine == self.engine).then_some(entry)
+    }
+
+    /// Fills in whatever results we have for this document. Returns false on a cache miss.
+    pub fn restore(&self, doc: &mut Document) -> bool {
+        match self.load(&self.entry_path(doc)) {
+            Some(entry) => {
+                doc.restore(entry.tokenization, entry.analysis);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Saves the document's tokenization and analysis, whichever of them are present.
+    pub fn store(&self, doc: &Document) -> anyhow::Result<()> {
+        let entry = CacheEntryRef {
+            engine: &self.engine,
+            tokenization: doc.tokenization(),
+            analysis: doc.analysis(),
+        };
+        let path = self.entry_path(doc);
+        // write then rename, so an interrupted run can't leave a truncated entry behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bincode::serialize(&entry)?)
+            .with_context(|| format!("Couldn't write '{}'", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Couldn't move '{}' into place", tmp_path.display()))?;
+        Ok(())
+    }
+
+    /// Deletes entries made by other engine versions. Returns how many were removed.
+    pub fn prune(&self) -> anyhow::Result<usize> {
+        let suffix = format!("-{:016x}.bin", self.engine_hash);
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)?.filter_map(|x| x.ok()) {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(".bin") && !name.ends_with(&suffix) {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
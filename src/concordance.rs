@@ -0,0 +1,123 @@
+//! Keyword-in-context (KWIC) lines, which line the matches of a search up in a column so
+//! they're easy to compare at a glance.
+use std::ops::Range;
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+const HIGHLIGHT_START: &str = "\x1b[1;33m";
+const HIGHLIGHT_END: &str = "\x1b[0m";
+
+/// A match of a search within a cue of an indexed document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hit {
+    pub doc: u32,
+    pub chunk: u32,
+    /// Byte range of the match in the text of the cue.
+    pub span: Range<usize>,
+}
+
+/// Finds the byte range in `text` covered by the pieces numbered `range`. The pieces, e.g. the
+/// tokens the engine split `text` into, have to appear in `text` in order, but may have had
+/// whitespace between them dropped.
+pub fn locate<'a>(
+    text: &str,
+    pieces: impl IntoIterator<Item = &'a str>,
+    range: Range<usize>,
+) -> Option<Range<usize>> {
+    let mut cursor = 0;
+    let mut start = None;
+    for (i, piece) in pieces.into_iter().enumerate().take(range.end) {
+        let at = cursor + text[cursor..].find(piece)?;
+        if i == range.start {
+            start = Some(at);
+        }
+        cursor = at + piece.len();
+    }
+    Some(start?..cursor)
+}
+
+pub struct KwicFormat {
+    /// Display width of the context on either side of the match.
+    pub width: usize,
+    /// Highlight the match with terminal colours, rather than brackets.
+    pub color: bool,
+}
+
+impl KwicFormat {
+    /// Formats `text` with the match at `span` starting at the same column on every line.
+    pub fn line(&self, text: &str, span: Range<usize>) -> String {
+        let left = truncate_start(&text[..span.start], self.width);
+        let right = truncate_end(&text[span.end..], self.width);
+        let matched = &text[span];
+        let padding = " ".repeat(self.width.saturating_sub(left.width()));
+        match self.color {
+            true => format!("{padding}{left} {HIGHLIGHT_START}{matched}{HIGHLIGHT_END} {right}"),
+            false => format!("{padding}{left}[{matched}]{right}"),
+        }
+    }
+}
+
+/// Cues can span several lines, which would break up the columns.
+pub fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+/// Keeps the end of `text` so it fits in `width` columns.
+fn truncate_start(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let mut kept = 0;
+    let mut used = 1; // the ellipsis
+    for c in text.chars().rev() {
+        used += c.width().unwrap_or(0);
+        if used > width {
+            break;
+        }
+        kept += c.len_utf8();
+    }
+    format!("…{}", &text[text.len() - kept..])
+}
+
+/// Keeps the start of `text` so it fits in `width` columns.
+fn truncate_end(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let mut kept = 0;
+    let mut used = 1;
+    for c in text.chars() {
+        used += c.width().unwrap_or(0);
+        if used > width {
+            break;
+        }
+        kept += c.len_utf8();
+    }
+    format!("{}…", &text[..kept])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locates_tokens_in_text() {
+        let text = "ボールを 取って";
+        let tokens = ["ボール", "を", "取っ", "て"];
+        let span = locate(text, tokens, 2..4).unwrap();
+        assert_eq!(&text[span], "取って");
+        assert_eq!(locate(text, ["ボール", "が"], 1..2), None);
+    }
+
+    #[test]
+    fn aligns_matches() {
+        let format = KwicFormat {
+            width: 6,
+            color: false,
+        };
+        let text = "赤くないボールを取ってください";
+        let span = locate(text, ["赤く", "ない", "ボール", "を", "取っ"], 4..5).unwrap();
+        assert_eq!(format.line(text, span), " …ルを[取っ]てく…");
+        assert_eq!(format.line("を取る", 3..9), "    を[取る]");
+    }
+}
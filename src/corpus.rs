@@ -0,0 +1,392 @@
+//! Find the subtitle files making up a corpus, e.g. a media library with one folder per show.
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::{
+    archive::{self, ArchiveKind},
+    args::CorpusArgs,
+    subs::{parse_subtitle_content_lenient, ParseIssue, ParsedSubtitles, SubtitleFormat},
+};
+
+/// How many bytes to read when we have to guess the format of a file from its contents.
+const SNIFF_LEN: u64 = 4096;
+
+pub struct CorpusOptions {
+    pub recursive: bool,
+    pub follow_symlinks: bool,
+    /// When `None`, every file with a known subtitle extension is included.
+    pub include: Option<GlobSet>,
+    pub exclude: Option<GlobSet>,
+}
+
+/// A subtitle file found while walking the corpus.
+#[derive(Clone, Debug)]
+pub struct CorpusFile {
+    /// For archive members, this is the archive's path joined with the member's path inside it.
+    pub path: PathBuf,
+    pub format: SubtitleFormat,
+    /// Contents of archive members, which have to be read while walking the archive.
+    pub archived: Option<Vec<u8>>,
+}
+
+impl CorpusFile {
+    /// Reads and parses the file, keeping whatever cues can be recovered from it.
+    /// Only fails if the file can't be read at all.
+    pub fn parse(&self) -> anyhow::Result<ParsedSubtitles> {
+        let read;
+        let bytes = match &self.archived {
+            Some(data) => data.as_slice(),
+            None => {
+                read = fs::read(&self.path)
+                    .with_context(|| format!("Failed to read file at '{}'", self.path.display()))?;
+                read.as_slice()
+            }
+        };
+        let content = String::from_utf8_lossy(bytes);
+        let mut parsed = parse_subtitle_content_lenient(&content, self.format);
+        if let Err(e) = std::str::from_utf8(bytes) {
+            let line = bytes[..e.valid_up_to()]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count()
+                + 1;
+            parsed.issues.insert(
+                0,
+                ParseIssue {
+                    line,
+                    reason: "invalid UTF-8, undecodable characters were replaced".into(),
+                },
+            );
+        }
+        Ok(parsed)
+    }
+}
+
+/// All the files found in a single directory, which we take to be a single show. The top level
+/// of an archive counts as a directory of its own.
+#[derive(Clone, Debug)]
+pub struct CorpusGroup {
+    pub dir: PathBuf,
+    pub files: Vec<CorpusFile>,
+}
+
+impl CorpusGroup {
+    pub fn name(&self) -> String {
+        self.dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.dir.display().to_string())
+    }
+}
+
+fn build_globset(patterns: &[String]) -> anyhow::Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid pattern '{pattern}'"))?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+impl CorpusOptions {
+    pub fn from_args(args: &CorpusArgs) -> anyhow::Result<Self> {
+        Ok(Self {
+            recursive: args.recursive,
+            follow_symlinks: args.follow_symlinks,
+            include: build_globset(&args.include)?,
+            exclude: build_globset(&args.exclude)?,
+        })
+    }
+
+    fn is_excluded(&self, relative: &Path) -> bool {
+        self.exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(relative))
+    }
+
+    /// Whether a file belongs in the corpus, going by its path alone.
+    fn wants(&self, path: &Path, relative: &Path) -> bool {
+        if self.is_excluded(relative) {
+            return false;
+        }
+        match &self.include {
+            // explicitly included files may have any extension, we'll look inside them
+            Some(include) => include.is_match(relative),
+            None => SubtitleFormat::from_extension(path).is_some(),
+        }
+    }
+}
+
+fn sniff_file(path: &Path) -> Option<SubtitleFormat> {
+    let mut head = vec![];
+    fs::File::open(path)
+        .ok()?
+        .take(SNIFF_LEN)
+        .read_to_end(&mut head)
+        .ok()?;
+    sniff_bytes(&head)
+}
+
+fn sniff_bytes(data: &[u8]) -> Option<SubtitleFormat> {
+    let head = &data[..data.len().min(SNIFF_LEN as usize)];
+    SubtitleFormat::sniff(&String::from_utf8_lossy(head))
+}
+
+/// Adds the subtitle files inside an archive to `groups`, keyed by their folder in the archive.
+fn discover_archive(
+    path: &Path,
+    relative: &Path,
+    kind: ArchiveKind,
+    options: &CorpusOptions,
+    groups: &mut BTreeMap<PathBuf, Vec<CorpusFile>>,
+) -> anyhow::Result<()> {
+    let members =
+        archive::read_members(path, kind, |name| options.wants(name, &relative.join(name)))?;
+    for member in members {
+        let Some(format) =
+            SubtitleFormat::from_extension(&member.name).or_else(|| sniff_bytes(&member.data))
+        else {
+            continue;
+        };
+        let member_path = path.join(&member.name);
+        let dir = member_path.parent().unwrap_or(path).to_path_buf();
+        groups.entry(dir).or_default().push(CorpusFile {
+            path: member_path,
+            format,
+            archived: Some(member.data),
+        });
+    }
+    Ok(())
+}
+
+/// Walks `root` looking for subtitle files, grouped by the directory they're in.
+/// Groups and the files within them are sorted by path so runs are reproducible.
+/// Directories, files and archives that can't be read are noted in `report` and skipped.
+pub fn discover(
+    root: &Path,
+    options: &CorpusOptions,
+    report: &mut IngestReport,
+) -> anyhow::Result<Vec<CorpusGroup>> {
+    if !root.is_dir() {
+        anyhow::bail!("Directory not found: {}", root.display());
+    }
+
+    let mut groups: BTreeMap<PathBuf, Vec<CorpusFile>> = BTreeMap::new();
+    let mut visited_dirs = HashSet::new();
+    let mut seen_files = HashSet::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        // guards against symlink loops
+        match fs::canonicalize(&dir) {
+            Ok(canonical) => {
+                if !visited_dirs.insert(canonical) {
+                    continue;
+                }
+            }
+            Err(e) => {
+                report.fail(&dir, format!("Couldn't read directory: {e}"), vec![]);
+                continue;
+            }
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                report.fail(&dir, format!("Couldn't read directory: {e}"), vec![]);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    report.fail(&dir, format!("Couldn't read directory: {e}"), vec![]);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    report.fail(&path, e.to_string(), vec![]);
+                    continue;
+                }
+            };
+            let is_symlink = file_type.is_symlink();
+            // follows symlinks, unlike `file_type`
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue; // dangling symlink
+            };
+
+            if metadata.is_dir() {
+                if options.recursive
+                    && (!is_symlink || options.follow_symlinks)
+                    && !options.is_excluded(relative)
+                {
+                    pending.push(path);
+                }
+            } else if metadata.is_file() {
+                // the same file may be reachable through several links
+                match fs::canonicalize(&path) {
+                    Ok(canonical) => {
+                        if !seen_files.insert(canonical) {
+                            continue;
+                        }
+                    }
+                    Err(e) => {
+                        report.fail(&path, e.to_string(), vec![]);
+                        continue;
+                    }
+                }
+                if let Some(kind) = ArchiveKind::from_path(&path) {
+                    if options.is_excluded(relative) {
+                        continue;
+                    }
+                    if let Err(e) = discover_archive(&path, relative, kind, options, &mut groups) {
+                        report.fail(&path, format!("{e:#}"), vec![]);
+                    }
+                } else if options.wants(&path, relative) {
+                    if let Some(format) =
+                        SubtitleFormat::from_extension(&path).or_else(|| sniff_file(&path))
+                    {
+                        groups.entry(dir.clone()).or_default().push(CorpusFile {
+                            path,
+                            format,
+                            archived: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|(dir, mut files)| {
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            CorpusGroup { dir, files }
+        })
+        .collect())
+}
+
+/// Problems found with a single file while ingesting a corpus.
+#[derive(Debug)]
+pub struct IngestProblem {
+    pub path: PathBuf,
+    /// Why we gave up on the file entirely, if we did.
+    pub error: Option<String>,
+    /// Parts of the file that were skipped.
+    pub issues: Vec<ParseIssue>,
+}
+
+/// Collects everything that went wrong while ingesting a corpus, so a few bad files don't stop
+/// the rest from being processed.
+#[derive(Debug, Default)]
+pub struct IngestReport {
+    pub ingested: usize,
+    pub problems: Vec<IngestProblem>,
+}
+
+impl IngestReport {
+    /// Records a file we couldn't get anything out of.
+    pub fn fail(&mut self, path: &Path, error: impl Into<String>, issues: Vec<ParseIssue>) {
+        self.problems.push(IngestProblem {
+            path: path.to_path_buf(),
+            error: Some(error.into()),
+            issues,
+        });
+    }
+
+    /// Records a file that was read, skipping over the given issues.
+    pub fn partial(&mut self, path: &Path, issues: Vec<ParseIssue>) {
+        if !issues.is_empty() {
+            self.problems.push(IngestProblem {
+                path: path.to_path_buf(),
+                error: None,
+                issues,
+            });
+        }
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &IngestProblem> {
+        self.problems.iter().filter(|p| p.error.is_some())
+    }
+}
+
+impl std::fmt::Display for IngestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let failed = self.failed().count();
+        write!(
+            f,
+            "Ingested {} files, {} failed, {} partially read",
+            self.ingested,
+            failed,
+            self.problems.len() - failed
+        )?;
+        for problem in &self.problems {
+            match &problem.error {
+                Some(error) => write!(f, "\n  [failed] {}: {error}", problem.path.display())?,
+                None => write!(f, "\n  [partial] {}", problem.path.display())?,
+            }
+            for issue in &problem.issues {
+                write!(f, "\n    {issue}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn discovers_archive_members_and_reports_broken_archives() {
+        let root = std::env::temp_dir().join(format!("omoide-corpus-test-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let mut zip = zip::ZipWriter::new(fs::File::create(root.join("Show.zip")).unwrap());
+        for name in ["Show/01.srt", "Show/02.srt", "Show/cover.jpg", "notes.txt"] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(b"1\n00:00:01,000 --> 00:00:02,000\nhello\n")
+                .unwrap();
+        }
+        zip.finish().unwrap();
+        fs::write(root.join("Broken.zip"), b"not a zip file").unwrap();
+
+        let options = CorpusOptions {
+            recursive: true,
+            follow_symlinks: false,
+            include: None,
+            exclude: None,
+        };
+        let mut report = IngestReport::default();
+        let groups = discover(&root, &options, &mut report);
+        fs::remove_dir_all(&root).unwrap();
+
+        let groups = groups.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].dir, root.join("Show.zip/Show"));
+        let files: Vec<&Path> = groups[0].files.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(
+            files,
+            [
+                root.join("Show.zip/Show/01.srt"),
+                root.join("Show.zip/Show/02.srt")
+            ]
+        );
+        assert!(groups[0].files[0].archived.is_some());
+        let failed: Vec<&Path> = report.failed().map(|p| p.path.as_path()).collect();
+        assert_eq!(failed, [root.join("Broken.zip")]);
+    }
+}
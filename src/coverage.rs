@@ -0,0 +1,141 @@
+//! Which kanji come up in a corpus and how often, so a learner can tell how many they'd need to
+//! read most of it, e.g. that the 500 most frequent kanji make up 90% of those in a show.
+use std::collections::{HashMap, HashSet};
+
+use crate::kanji::{is_kanji, KanjiLevel, ITERATION_MARK};
+
+/// Proportions of the kanji in a text to say how many kanji it takes to cover.
+pub const MILESTONES: [f32; 5] = [0.5, 0.75, 0.9, 0.95, 0.99];
+
+/// Occurrences of each kanji in a text.
+#[derive(Clone, Debug, Default)]
+pub struct KanjiCounts {
+    counts: HashMap<char, usize>,
+    total: usize,
+}
+
+/// A kanji in a coverage report.
+#[derive(Clone, Debug)]
+pub struct KanjiCoverage {
+    pub kanji: char,
+    pub count: usize,
+    /// Proportion of the kanji in the text that are this one or a more frequent one.
+    pub coverage: f32,
+    pub level: KanjiLevel,
+    /// Whether the learner already knows a word using it.
+    pub known: bool,
+}
+
+impl KanjiCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the kanji in `text`. An iteration mark counts as another of the kanji before it,
+    /// as in 人々, or not at all if there isn't one.
+    pub fn add(&mut self, text: &str) {
+        let mut last = None;
+        for c in text.chars() {
+            let kanji = match c {
+                ITERATION_MARK => last,
+                c if is_kanji(c) => Some(c),
+                _ => None,
+            };
+            if let Some(kanji) = kanji {
+                *self.counts.entry(kanji).or_default() += 1;
+                self.total += 1;
+            }
+            last = kanji;
+        }
+    }
+
+    /// Number of different kanji.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Occurrences of all kanji together.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Every kanji, most frequent first, with how much of the text it and the ones before it
+    /// cover. `level_of` tells how advanced a kanji is, and `known` has the kanji the learner
+    /// knows.
+    pub fn report(
+        &self,
+        level_of: impl Fn(char) -> KanjiLevel,
+        known: &HashSet<char>,
+    ) -> Vec<KanjiCoverage> {
+        let mut counts: Vec<(char, usize)> = self.counts.iter().map(|(&k, &n)| (k, n)).collect();
+        // ties broken by codepoint, so the report is the same every time
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut covered = 0;
+        counts
+            .into_iter()
+            .map(|(kanji, count)| {
+                covered += count;
+                KanjiCoverage {
+                    kanji,
+                    count,
+                    coverage: covered as f32 / self.total as f32,
+                    level: level_of(kanji),
+                    known: known.contains(&kanji),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How many of the most frequent kanji in a report it takes to cover `proportion` of the text.
+pub fn kanji_needed(report: &[KanjiCoverage], proportion: f32) -> Option<usize> {
+    report
+        .iter()
+        .position(|kanji| kanji.coverage >= proportion)
+        .map(|i| i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kanji_coverage_accumulates() {
+        let mut counts = KanjiCounts::new();
+        counts.add("日本の日曜日");
+        counts.add("本を読む");
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts.total(), 7);
+
+        let known = HashSet::from(['本']);
+        let report = counts.report(|_| KanjiLevel::default(), &known);
+        let kanji: Vec<(char, usize, bool)> = report
+            .iter()
+            .map(|kanji| (kanji.kanji, kanji.count, kanji.known))
+            .collect();
+        assert_eq!(
+            kanji,
+            [
+                ('日', 3, false),
+                ('本', 2, true),
+                ('曜', 1, false),
+                ('読', 1, false)
+            ]
+        );
+        assert_eq!(report[1].coverage, 5.0 / 7.0);
+        assert_eq!(kanji_needed(&report, 0.4), Some(1));
+        assert_eq!(kanji_needed(&report, 0.8), Some(3));
+        assert_eq!(kanji_needed(&report, 1.0), Some(4));
+
+        let mut counts = KanjiCounts::new();
+        counts.add("人々");
+        counts.add("々");
+        let report = counts.report(|_| KanjiLevel::default(), &known);
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].kanji, report[0].count), ('人', 2));
+    }
+}
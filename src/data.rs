@@ -0,0 +1,225 @@
+//! Dictionary data loaded at runtime from a data directory, rather than built into the binary.
+//! The XML releases of JMdict and KANJIDIC are imported once with `omoide manage`, which
+//! converts them into files that are quick to load.
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{dict, kanji};
+
+/// Bump whenever the imported files change shape.
+const DATA_FORMAT_VERSION: u32 = 1;
+
+/// JMdict is converted into a form that's quick to load, it's far too big to parse each time.
+pub const JMDICT_FILE: &str = "jmdict.bin";
+/// Names the JMdict XML release goes by, which is read as it is if it hasn't been imported.
+pub const JMDICT_XML_FILES: &[&str] = &["JMdict_e.xml", "JMdict_e", "JMdict.xml", "JMdict"];
+/// Dictionaries prebuilt by `omoide manage --build-index`, see `prebuilt`.
+pub const JMDICT_INDEX_FILE: &str = "jmdict.idx";
+pub const KANJIDIC_INDEX_FILE: &str = "kanjidic.idx";
+/// KANJIDIC is small enough to parse each time, and is kept as it is.
+pub const KANJIDIC_FILE: &str = "kanjidic2.xml";
+
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Where dictionaries are looked for, if not set with `set_data_dir`: `$OMOIDE_DATA_DIR`, or
+/// else `omoide` in the usual place for user data. Tests use the excerpts in `testdata/`.
+fn default_data_dir() -> PathBuf {
+    if cfg!(test) {
+        return PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata"));
+    }
+    if let Some(dir) = std::env::var_os("OMOIDE_DATA_DIR") {
+        return dir.into();
+    }
+    match (std::env::var_os("XDG_DATA_HOME"), std::env::var_os("HOME")) {
+        (Some(data_home), _) => PathBuf::from(data_home).join("omoide"),
+        (None, Some(home)) => PathBuf::from(home).join(".local/share/omoide"),
+        (None, None) => PathBuf::from(".omoide_data"),
+    }
+}
+
+/// Has dictionaries loaded from `dir`. Fails after the first lookup, which settles the data
+/// directory for good.
+pub fn set_data_dir(dir: PathBuf) -> anyhow::Result<()> {
+    DATA_DIR.set(dir).map_err(|dir| {
+        anyhow::anyhow!(
+            "Can't use '{}' for data, '{}' is in use already",
+            dir.display(),
+            data_dir().display()
+        )
+    })
+}
+
+pub fn data_dir() -> &'static Path {
+    DATA_DIR.get_or_init(default_data_dir)
+}
+
+/// Where an imported dictionary came from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DataInfo {
+    pub source: PathBuf,
+    /// Release of the dictionary, if it says.
+    pub version: Option<String>,
+    /// When the dictionary was put together, if it says.
+    pub created: Option<String>,
+    pub entries: usize,
+    /// Seconds since the Unix epoch.
+    pub imported: u64,
+}
+
+#[derive(Serialize)]
+struct DataFileRef<'a, T> {
+    format_version: u32,
+    data: &'a [T],
+}
+
+#[derive(Deserialize)]
+struct DataFile<T> {
+    format_version: u32,
+    data: Vec<T>,
+}
+
+/// Where what's known about an imported dictionary is kept.
+fn info_path(name: &str) -> PathBuf {
+    data_dir().join(name).with_extension("info")
+}
+
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create data directory '{}'", dir.display()))?;
+    }
+    // named after the whole file name, and the process, so concurrent writes of this file or
+    // others next to it can't move each other's unfinished files into place
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = PathBuf::from(tmp_path);
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Loads a dictionary converted on import, or returns `None` if there isn't a usable one.
+pub(crate) fn load<T: DeserializeOwned>(name: &str) -> Option<Vec<T>> {
+    let file = fs::File::open(data_dir().join(name)).ok()?;
+    let loaded: DataFile<T> = bincode::deserialize_from(BufReader::new(file)).ok()?;
+    (loaded.format_version == DATA_FORMAT_VERSION).then_some(loaded.data)
+}
+
+fn save<T: Serialize>(name: &str, data: &[T]) -> anyhow::Result<()> {
+    let file = DataFileRef {
+        format_version: DATA_FORMAT_VERSION,
+        data,
+    };
+    write_atomically(&data_dir().join(name), &bincode::serialize(&file)?)
+}
+
+fn save_info(name: &str, info: &DataInfo) -> anyhow::Result<()> {
+    write_atomically(
+        &info_path(name),
+        &bincode::serialize(&(DATA_FORMAT_VERSION, info))?,
+    )
+}
+
+/// Describes an imported dictionary, or returns `None` if it wasn't imported.
+pub fn info(name: &str) -> Option<DataInfo> {
+    let bytes = fs::read(info_path(name)).ok()?;
+    let (format_version, info): (u32, DataInfo) = bincode::deserialize(&bytes).ok()?;
+    (format_version == DATA_FORMAT_VERSION).then_some(info)
+}
+
+/// The first of `names` that's a file in the data directory.
+pub(crate) fn find_xml(names: &[&str]) -> Option<PathBuf> {
+    names
+        .iter()
+        .map(|name| data_dir().join(name))
+        .find(|path| path.is_file())
+}
+
+pub(crate) fn read_xml(path: &Path) -> anyhow::Result<String> {
+    fs::read_to_string(path).with_context(|| format!("Failed to read '{}'", path.display()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Reads a JMdict XML release into the data directory.
+pub fn import_jmdict(path: &Path) -> anyhow::Result<DataInfo> {
+    let xml = read_xml(path)?;
+    let (entries, created) =
+        dict::parse_jmdict(&xml).with_context(|| format!("'{}' isn't JMdict", path.display()))?;
+    anyhow::ensure!(!entries.is_empty(), "No entries in '{}'", path.display());
+    let info = DataInfo {
+        source: path.to_path_buf(),
+        version: None,
+        created,
+        entries: entries.len(),
+        imported: now(),
+    };
+    save(JMDICT_FILE, &entries)?;
+    save_info(JMDICT_FILE, &info)?;
+    Ok(info)
+}
+
+/// Copies a KANJIDIC2 XML release into the data directory, once it's made sure it can be read.
+pub fn import_kanjidic(path: &Path) -> anyhow::Result<DataInfo> {
+    let xml = read_xml(path)?;
+    let (characters, version, created) = kanji::parse_kanjidic(&xml)
+        .with_context(|| format!("'{}' isn't KANJIDIC2", path.display()))?;
+    anyhow::ensure!(!characters.is_empty(), "No kanji in '{}'", path.display());
+    let info = DataInfo {
+        source: path.to_path_buf(),
+        version: Some(version),
+        created: Some(created),
+        entries: characters.len(),
+        imported: now(),
+    };
+    write_atomically(&data_dir().join(KANJIDIC_FILE), xml.as_bytes())?;
+    save_info(KANJIDIC_FILE, &info)?;
+    Ok(info)
+}
+
+/// Checks that an imported dictionary loads in full, with as many entries as were imported.
+fn verify(
+    name: &str,
+    load: impl FnOnce(&Path) -> anyhow::Result<usize>,
+) -> anyhow::Result<DataInfo> {
+    let path = data_dir().join(name);
+    let info = info(name).with_context(|| format!("Nothing imported at '{}'", path.display()))?;
+    let entries = load(&path).with_context(|| {
+        format!(
+            "'{}' is corrupt or from another version, import it again",
+            path.display()
+        )
+    })?;
+    anyhow::ensure!(
+        entries == info.entries,
+        "'{}' has {entries} entries, but {} were imported",
+        path.display(),
+        info.entries
+    );
+    Ok(info)
+}
+
+pub fn verify_jmdict() -> anyhow::Result<DataInfo> {
+    verify(JMDICT_FILE, |_| {
+        load::<dict::Entry>(JMDICT_FILE)
+            .map(|entries| entries.len())
+            .context("Couldn't load it")
+    })
+}
+
+pub fn verify_kanjidic() -> anyhow::Result<DataInfo> {
+    verify(KANJIDIC_FILE, |path| {
+        let (characters, _, _) = kanji::parse_kanjidic(&read_xml(path)?)?;
+        Ok(characters.len())
+    })
+}
@@ -1,26 +1,164 @@
-use std::{collections::HashMap, iter, ops::BitXor};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, iter,
+    ops::BitXor,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    document::Document,
+    args::DedupArgs,
+    document::{Document, DocumentChunk},
     nlp::{DocumentTokenization, Engine},
+    subs::SubtitleFormat,
 };
 
-pub fn doc_minhash(input: &DocumentTokenization) -> Vec<u64> {
-    let hashers: Vec<u64> = (42..242).map(|v| fxhash::hash64(&v)).collect();
-    let mut minhashes = Vec::with_capacity(200);
+/// Bump whenever the saved set changes shape.
+const SAVED_FORMAT_VERSION: u32 = 2;
+
+/// How documents are cut up into overlapping pieces to be compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shingling {
+    /// Runs of this many tokens. Needs the documents to be tokenized by the NLP engine.
+    Tokens(usize),
+    /// Runs of this many characters, ignoring whitespace. Doesn't need the NLP engine at all.
+    Characters(usize),
+}
+
+impl Default for Shingling {
+    fn default() -> Self {
+        Self::Tokens(3)
+    }
+}
+
+/// What to make shingles out of, as picked on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ShingleUnit {
+    Tokens,
+    Chars,
+}
+
+impl Shingling {
+    /// Uses a size that works well for the unit when none is given.
+    pub fn new(unit: ShingleUnit, size: Option<usize>) -> Self {
+        match unit {
+            ShingleUnit::Tokens => Self::Tokens(size.unwrap_or(3)),
+            ShingleUnit::Chars => Self::Characters(size.unwrap_or(5)),
+        }
+    }
+
+    pub fn needs_tokenization(&self) -> bool {
+        matches!(self, Self::Tokens(_))
+    }
+
+    /// Hashes of the document's shingles, which must be tokenized when shingling by tokens.
+    pub fn shingles(&self, doc: &Document) -> Vec<u64> {
+        match *self {
+            Self::Tokens(size) => {
+                let tokenization = doc.tokenization().expect("document must be tokenized");
+                token_shingles(tokenization, size)
+            }
+            Self::Characters(size) => {
+                let chars: Vec<char> = doc
+                    .contents()
+                    .flat_map(str::chars)
+                    .filter(|c| !c.is_whitespace())
+                    .collect();
+                windows(&chars, size).map(fxhash::hash64).collect()
+            }
+        }
+    }
+}
+
+/// Overlapping runs of `size` items. Inputs shorter than that are a single shingle, rather than
+/// none at all.
+fn windows<T>(items: &[T], size: usize) -> impl Iterator<Item = &[T]> {
+    let size = size.clamp(1, items.len().max(1));
+    items.windows(size)
+}
+
+pub fn token_shingles(input: &DocumentTokenization, size: usize) -> Vec<u64> {
+    let tokens: Vec<&str> = input
+        .tokenization
+        .iter()
+        .flatten()
+        .map(String::as_str)
+        .collect();
+    windows(&tokens, size).map(fxhash::hash64).collect()
+}
+
+/// Signature of a set of shingles made of `num_hashes` hashes, or `None` if there are no
+/// shingles, as there's nothing to compare.
+///
+/// Each shingle is hashed once, and each of the signature's hash functions is a cheap
+/// permutation of that hash, so the shingles only need going over once.
+pub fn minhash(shingles: &[u64], num_hashes: usize) -> Option<Vec<u64>> {
+    if shingles.is_empty() {
+        return None;
+    }
+    // multiplying by an odd number is a permutation of u64s, as is adding anything
+    let permutations: Vec<(u64, u64)> = (42..42 + num_hashes as u64)
+        .map(|v| (fxhash::hash64(&v) | 1, fxhash::hash64(&v.bitxor(u64::MAX))))
+        .collect();
+    let mut shingles = shingles.to_vec();
+    shingles.sort_unstable();
+    shingles.dedup();
+
+    let mut signature = vec![u64::MAX; num_hashes];
+    for shingle in shingles {
+        for (min, &(a, b)) in iter::zip(&mut signature, &permutations) {
+            *min = (*min).min(shingle.wrapping_mul(a).wrapping_add(b));
+        }
+    }
+    Some(signature)
+}
 
-    let input: Vec<String> = input.tokenization.iter().cloned().flatten().collect();
+/// Parameters for locality sensitive hashing of documents. Signatures are split into bands of
+/// `band_size` hashes, and documents sharing any band are compared in full. Smaller bands find
+/// more candidate pairs at lower similarities, at the cost of more comparisons.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DedupConfig {
+    pub shingling: Shingling,
+    pub num_hashes: usize,
+    pub band_size: usize,
+    /// Documents with an estimated Jaccard similarity above this are duplicates.
+    pub threshold: f32,
+}
 
-    for j in 0..200 {
-        let mut minhash = u64::MAX;
-        for i in 1..input.len() - 1 {
-            let hash = fxhash::hash64(&format!("{}{}{}", input[i - 1], input[i], input[i + 1]));
-            minhash = hash.bitxor(hashers[j]).min(minhash);
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            shingling: Shingling::default(),
+            num_hashes: 200,
+            band_size: 4,
+            threshold: 0.8,
         }
-        minhashes.push(minhash);
     }
+}
 
-    minhashes
+impl DedupConfig {
+    pub fn from_args(args: &DedupArgs) -> anyhow::Result<Self> {
+        if args.num_hashes == 0 || args.band_size == 0 || args.band_size > args.num_hashes {
+            anyhow::bail!(
+                "Band size must be between 1 and the number of hashes ({}), got {}",
+                args.num_hashes,
+                args.band_size
+            );
+        }
+        if !(0.0..=1.0).contains(&args.dedup_threshold) {
+            anyhow::bail!(
+                "Duplicate threshold must be between 0 and 1, got {}",
+                args.dedup_threshold
+            );
+        }
+        Ok(Self {
+            shingling: Shingling::new(args.shingle, args.shingle_size),
+            num_hashes: args.num_hashes,
+            band_size: args.band_size,
+            threshold: args.dedup_threshold,
+        })
+    }
 }
 
 pub fn minhash_jaccard_similarity(a: &[u64], b: &[u64]) -> f32 {
@@ -36,76 +174,790 @@ pub fn minhash_jaccard_similarity(a: &[u64], b: &[u64]) -> f32 {
     matched as f32 / a.len() as f32
 }
 
+/// What we need to know about a document to pick between it and its duplicates, which is kept
+/// on disk along with its signature so it can be compared without loading the document again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocumentSummary {
+    pub source: Option<PathBuf>,
+    pub content_hash: u64,
+    pub release_group: Option<String>,
+    pub format: Option<SubtitleFormat>,
+    pub cues: usize,
+    pub has_timing: bool,
+    /// Characters that were replaced because they couldn't be decoded.
+    pub undecodable: usize,
+}
+
+impl DocumentSummary {
+    pub fn new(doc: &Document) -> Self {
+        Self {
+            source: doc.source().map(Path::to_path_buf),
+            content_hash: doc.content_hash(),
+            release_group: doc.metadata().release_group.clone(),
+            format: doc.source().and_then(SubtitleFormat::from_extension),
+            cues: doc.chunks().len(),
+            has_timing: doc.chunks().iter().any(|chunk| match chunk {
+                DocumentChunk::Subs(sub) => !sub.end.is_zero(),
+                DocumentChunk::Plaintext(_) => false,
+            }),
+            undecodable: doc
+                .contents()
+                .flat_map(str::chars)
+                .filter(|&c| c == char::REPLACEMENT_CHARACTER)
+                .count(),
+        }
+    }
+}
+
+/// Decides which of two near-duplicate documents to keep, e.g. two releases of the same episode.
+pub trait DuplicatePolicy: Send + Sync {
+    /// Whether `candidate` should take the place of `existing`, which is already in the set.
+    fn prefer(&self, candidate: &DocumentSummary, existing: &DocumentSummary) -> bool;
+
+    /// Identifies the policy and its settings, so decisions saved to disk under a different
+    /// policy aren't reused.
+    fn describe(&self) -> String;
+}
+
+/// Keeps whichever document was inserted first.
+pub struct FirstWins;
+
+impl DuplicatePolicy for FirstWins {
+    fn prefer(&self, _candidate: &DocumentSummary, _existing: &DocumentSummary) -> bool {
+        false
+    }
+
+    fn describe(&self) -> String {
+        "first-wins".into()
+    }
+}
+
+/// Keeps the better release: from a preferred release group or in a preferred format if any
+/// were given, then one with timing, then one with fewer undecodable characters, then the one
+/// with more cues. Ties go to the document inserted first.
+#[derive(Clone, Debug, Default)]
+pub struct QualityPolicy {
+    /// Most preferred first.
+    pub release_groups: Vec<String>,
+    /// Most preferred first.
+    pub formats: Vec<SubtitleFormat>,
+}
+
+impl QualityPolicy {
+    fn quality(&self, doc: &DocumentSummary) -> impl Ord {
+        // earlier in the list is better, not being in it at all is worst
+        let preference = |i: Option<usize>| std::cmp::Reverse(i.unwrap_or(usize::MAX));
+        let release_group = doc.release_group.as_deref().and_then(|group| {
+            self.release_groups
+                .iter()
+                .position(|preferred| preferred.eq_ignore_ascii_case(group))
+        });
+        let format = doc
+            .format
+            .and_then(|format| self.formats.iter().position(|&f| f == format));
+
+        (
+            preference(release_group),
+            preference(format),
+            doc.has_timing,
+            std::cmp::Reverse(doc.undecodable),
+            doc.cues,
+        )
+    }
+}
+
+impl DuplicatePolicy for QualityPolicy {
+    fn prefer(&self, candidate: &DocumentSummary, existing: &DocumentSummary) -> bool {
+        self.quality(candidate) > self.quality(existing)
+    }
+
+    fn describe(&self) -> String {
+        format!("{self:?}")
+    }
+}
+
+/// MinHash signature of a document, worked out ahead of inserting it.
+#[derive(Clone, Debug)]
+pub struct Signature(Option<Vec<u64>>);
+
+/// What became of a document inserted into a `DocumentDedupSet`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Inserted {
+    /// Added at this index.
+    New(usize),
+    /// Took the place of a worse near-duplicate at this index.
+    Replaced(usize),
+    /// Left out as a near-duplicate of the document at this index.
+    Duplicate(usize),
+}
+
+impl Inserted {
+    /// Index of the document if it made it into the set.
+    pub fn kept(self) -> Option<usize> {
+        match self {
+            Self::New(i) | Self::Replaced(i) => Some(i),
+            Self::Duplicate(_) => None,
+        }
+    }
+}
+
+/// A document that was left out of the set for being too similar to one in it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Duplicate {
+    pub summary: DocumentSummary,
+    /// Index of the document in the set it duplicates.
+    pub kept: usize,
+    pub similarity: f32,
+    signature: Vec<u64>,
+}
+
+/// A document in the set along with the ones dropped as its duplicates.
+#[derive(Clone, Debug)]
+pub struct DuplicateCluster<'a> {
+    pub kept: &'a DocumentSummary,
+    pub duplicates: Vec<&'a Duplicate>,
+    /// Estimated Jaccard similarity between every pair of files in the cluster, with the kept
+    /// one first.
+    pub similarities: Vec<Vec<f32>>,
+}
+
+impl DuplicateCluster<'_> {
+    /// Sources of the files in the cluster, in the same order as `similarities`.
+    pub fn sources(&self) -> impl Iterator<Item = Option<&Path>> {
+        iter::once(self.kept.source.as_deref())
+            .chain(self.duplicates.iter().map(|d| d.summary.source.as_deref()))
+    }
+}
+
+/// A document kept in the set. Entries loaded from disk have no document until the same
+/// document is inserted again.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    summary: DocumentSummary,
+    /// `None` if the document has no shingles at all.
+    signature: Option<Vec<u64>>,
+    #[serde(skip)]
+    doc: Option<Document>,
+}
+
+/// Where a document we've seen before ended up.
+#[derive(Clone, Copy, Debug)]
+enum Seen {
+    Kept(usize),
+    Duplicate(usize),
+}
+
+/// The parts of the set that are saved between runs.
+#[derive(Serialize, Deserialize)]
+struct SavedSet {
+    format_version: u32,
+    /// Config and policy the decisions were made with.
+    settings: String,
+    entries: Vec<Entry>,
+    duplicates: Vec<Duplicate>,
+    doc_map: HashMap<(u32, u64), Vec<usize>>,
+}
+
 pub struct DocumentDedupSet {
-    _docs: Vec<(Document, Vec<u64>)>,
+    entries: Vec<Entry>,
     doc_map: HashMap<(u32, u64), Vec<usize>>,
+    duplicates: Vec<Duplicate>,
+    seen: HashMap<u64, Seen>,
+    /// Signatures of documents dropped from a saved set because the document they duplicated
+    /// is gone, so they don't have to be worked out again when they're inserted.
+    signatures: HashMap<u64, Option<Vec<u64>>>,
+    config: DedupConfig,
+    policy: Box<dyn DuplicatePolicy>,
 }
 
 impl DocumentDedupSet {
     pub fn new() -> Self {
+        Self::with_config(DedupConfig::default())
+    }
+
+    pub fn with_config(config: DedupConfig) -> Self {
         Self {
-            _docs: vec![],
+            entries: vec![],
             doc_map: HashMap::new(),
+            duplicates: vec![],
+            seen: HashMap::new(),
+            signatures: HashMap::new(),
+            config,
+            policy: Box::new(QualityPolicy::default()),
+        }
+    }
+
+    /// Sets up the set as asked for on the command line.
+    pub fn from_args(args: &DedupArgs) -> anyhow::Result<Self> {
+        let mut set = Self::with_config(DedupConfig::from_args(args)?);
+        if args.keep_first {
+            set.set_policy(FirstWins);
+        } else {
+            set.set_policy(QualityPolicy {
+                release_groups: args.prefer_group.clone(),
+                formats: args.prefer_format.clone(),
+            });
+        }
+        Ok(set)
+    }
+
+    pub fn set_policy(&mut self, policy: impl DuplicatePolicy + 'static) {
+        self.policy = Box::new(policy);
+    }
+
+    pub fn config(&self) -> &DedupConfig {
+        &self.config
+    }
+
+    fn settings(&self) -> String {
+        format!("{:?}/{}", self.config, self.policy.describe())
+    }
+
+    /// Picks up signatures and decisions saved by an earlier run with the same settings, so only
+    /// new documents have to be shingled. Anything not in `present`, a set of content hashes,
+    /// has since been removed from the corpus and is forgotten.
+    pub fn load(&mut self, path: &Path, present: &HashSet<u64>) {
+        let Some(saved) = fs::read(path)
+            .ok()
+            .and_then(|bytes| bincode::deserialize::<SavedSet>(&bytes).ok())
+        else {
+            return;
+        };
+        if saved.format_version != SAVED_FORMAT_VERSION || saved.settings != self.settings() {
+            return;
+        }
+
+        let is_present = |summary: &DocumentSummary| present.contains(&summary.content_hash);
+        if saved.entries.iter().all(|entry| is_present(&entry.summary))
+            && saved.duplicates.iter().all(|d| is_present(&d.summary))
+        {
+            self.entries = saved.entries;
+            self.duplicates = saved.duplicates;
+            self.doc_map = saved.doc_map;
+        } else {
+            // renumber what's left, and have duplicates of removed documents inserted afresh
+            let mut renumbered = HashMap::new();
+            for (i, entry) in saved.entries.into_iter().enumerate() {
+                if is_present(&entry.summary) {
+                    renumbered.insert(i, self.entries.len());
+                    self.entries.push(entry);
+                }
+            }
+            for mut duplicate in saved.duplicates {
+                match renumbered.get(&duplicate.kept) {
+                    Some(&kept) if is_present(&duplicate.summary) => {
+                        duplicate.kept = kept;
+                        self.duplicates.push(duplicate);
+                    }
+                    _ => {
+                        self.signatures
+                            .insert(duplicate.summary.content_hash, Some(duplicate.signature));
+                    }
+                }
+            }
+            for i in 0..self.entries.len() {
+                if let Some(signature) = &self.entries[i].signature {
+                    for (band_i, band) in self.bands(signature).into_iter().enumerate() {
+                        self.doc_map
+                            .entry((band_i as u32, band))
+                            .or_default()
+                            .push(i);
+                    }
+                }
+            }
+        }
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            self.seen.insert(entry.summary.content_hash, Seen::Kept(i));
+        }
+        for (i, duplicate) in self.duplicates.iter().enumerate() {
+            self.seen
+                .insert(duplicate.summary.content_hash, Seen::Duplicate(i));
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct SavedSetRef<'a> {
+            format_version: u32,
+            settings: String,
+            entries: &'a [Entry],
+            duplicates: &'a [Duplicate],
+            doc_map: &'a HashMap<(u32, u64), Vec<usize>>,
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
         }
+        let saved = SavedSetRef {
+            format_version: SAVED_FORMAT_VERSION,
+            settings: self.settings(),
+            entries: &self.entries,
+            duplicates: &self.duplicates,
+            doc_map: &self.doc_map,
+        };
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bincode::serialize(&saved)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    fn bands(&self, signature: &[u64]) -> Vec<u64> {
+        signature
+            .chunks(self.config.band_size)
+            .map(fxhash::hash64)
+            .collect()
     }
 
-    fn insert_inner(&mut self, doc: Document, minhashes: Vec<u64>) -> Option<usize> {
-        let bands: Vec<u64> = minhashes.as_slice().chunks(4).map(fxhash::hash64).collect();
+    /// Whether the document has to be tokenized before it's inserted.
+    pub fn needs_tokenization(&self, doc: &Document) -> bool {
+        let content_hash = doc.content_hash();
+        self.config.shingling.needs_tokenization()
+            && !self.seen.contains_key(&content_hash)
+            && !self.signatures.contains_key(&content_hash)
+    }
+
+    fn insert_inner(
+        &mut self,
+        doc: Document,
+        summary: DocumentSummary,
+        minhashes: Option<Vec<u64>>,
+    ) -> Inserted {
+        // empty documents have nothing to be a duplicate of
+        let Some(minhashes) = minhashes else {
+            return self.push(doc, summary, None);
+        };
+
+        let bands = self.bands(&minhashes);
+        let mut best: Option<(usize, f32)> = None;
         for (band_i, band_hash) in bands.iter().enumerate() {
             if let Some(candidates) = self.doc_map.get(&(band_i as u32, *band_hash)) {
-                for candidate in candidates {
-                    if minhash_jaccard_similarity(&self._docs[*candidate].1, &minhashes) > 0.8 {
-                        return None;
+                for &candidate in candidates {
+                    let signature = self.entries[candidate].signature.as_deref().unwrap();
+                    let similarity = minhash_jaccard_similarity(signature, &minhashes);
+                    if best.is_none_or(|(_, best)| similarity > best) {
+                        best = Some((candidate, similarity));
                     }
                 }
             }
         }
 
+        if let Some((kept, similarity)) = best.filter(|(_, s)| *s > self.config.threshold) {
+            if !self.policy.prefer(&summary, &self.entries[kept].summary) {
+                self.push_duplicate(summary, kept, similarity, minhashes);
+                return Inserted::Duplicate(kept);
+            }
+            self.replace(kept, doc, summary, minhashes, bands, similarity);
+            return Inserted::Replaced(kept);
+        }
+
         for (i, band) in bands.iter().enumerate() {
             self.doc_map
                 .entry((i as u32, *band))
                 .or_insert(vec![])
-                .push(self._docs.len());
+                .push(self.entries.len());
         }
+        self.push(doc, summary, Some(minhashes))
+    }
 
-        self._docs.push((doc, minhashes));
+    fn push(
+        &mut self,
+        doc: Document,
+        summary: DocumentSummary,
+        signature: Option<Vec<u64>>,
+    ) -> Inserted {
+        self.seen
+            .insert(summary.content_hash, Seen::Kept(self.entries.len()));
+        self.entries.push(Entry {
+            summary,
+            signature,
+            doc: Some(doc),
+        });
+        Inserted::New(self.entries.len() - 1)
+    }
 
-        Some(self._docs.len() - 1)
+    fn push_duplicate(
+        &mut self,
+        summary: DocumentSummary,
+        kept: usize,
+        similarity: f32,
+        signature: Vec<u64>,
+    ) {
+        self.seen
+            .insert(summary.content_hash, Seen::Duplicate(self.duplicates.len()));
+        self.duplicates.push(Duplicate {
+            summary,
+            kept,
+            similarity,
+            signature,
+        });
     }
 
-    pub async fn insert(
+    /// Puts `doc` in place of the document at `index`, which becomes one of its duplicates.
+    fn replace(
         &mut self,
-        engine: &Engine,
-        mut doc: Document,
-    ) -> anyhow::Result<Option<usize>> {
-        doc.tokenize(engine).await?;
+        index: usize,
+        doc: Document,
+        summary: DocumentSummary,
+        minhashes: Vec<u64>,
+        bands: Vec<u64>,
+        similarity: f32,
+    ) {
+        self.seen.insert(summary.content_hash, Seen::Kept(index));
+        let previous = std::mem::replace(
+            &mut self.entries[index],
+            Entry {
+                summary,
+                signature: Some(minhashes),
+                doc: Some(doc),
+            },
+        );
+        let previous_minhashes = previous.signature.unwrap();
+
+        for (i, band) in self.bands(&previous_minhashes).into_iter().enumerate() {
+            if let Some(bucket) = self.doc_map.get_mut(&(i as u32, band)) {
+                bucket.retain(|&doc| doc != index);
+            }
+        }
+        for (i, band) in bands.into_iter().enumerate() {
+            self.doc_map
+                .entry((i as u32, band))
+                .or_default()
+                .push(index);
+        }
+
+        // earlier duplicates now belong to the new document
+        let signature = self.entries[index].signature.as_deref().unwrap();
+        for duplicate in self.duplicates.iter_mut().filter(|d| d.kept == index) {
+            duplicate.similarity = minhash_jaccard_similarity(&duplicate.signature, signature);
+        }
+        self.push_duplicate(previous.summary, index, similarity, previous_minhashes);
+    }
+
+    pub async fn insert(&mut self, engine: &Engine, mut doc: Document) -> anyhow::Result<Inserted> {
+        if self.needs_tokenization(&doc) {
+            doc.tokenize(engine).await?;
+        }
         Ok(self.insert_tokenized(doc))
     }
 
-    pub fn insert_tokenized(&mut self, doc: Document) -> Option<usize> {
-        let minhashes = doc_minhash(doc.tokenization().expect("document must be tokenized"));
-        self.insert_inner(doc, minhashes)
+    /// Inserts a document which has already been tokenized, if `needs_tokenization` says so.
+    pub fn insert_tokenized(&mut self, doc: Document) -> Inserted {
+        let signature = self.signature(&doc);
+        self.insert_signed(doc, signature)
     }
 
+    /// Works out the signature of a document to be inserted, which is most of the work of
+    /// inserting it. Only needs `&self`, so it can be done for many documents at once, see
+    /// `insert_signed`. The document has to be tokenized if `needs_tokenization` says so.
+    ///
+    /// It goes by the set as it is before any of those documents are inserted, so a document
+    /// repeated within them is signed like a new one; `insert_signed` catches that.
+    pub fn signature(&self, doc: &Document) -> Signature {
+        let content_hash = doc.content_hash();
+        if self.seen.contains_key(&content_hash) {
+            // won't be needed
+            return Signature(None);
+        }
+        match self.signatures.get(&content_hash) {
+            Some(minhashes) => Signature(minhashes.clone()),
+            None => Signature(minhash(
+                &self.config.shingling.shingles(doc),
+                self.config.num_hashes,
+            )),
+        }
+    }
+
+    /// Inserts a document along with its signature from `signature`. The signature may predate
+    /// other insertions, so whether the document was seen is checked again here rather than
+    /// trusted to `signature`.
+    pub fn insert_signed(&mut self, doc: Document, signature: Signature) -> Inserted {
+        let summary = DocumentSummary::new(&doc);
+        match self.seen.get(&summary.content_hash).copied() {
+            Some(Seen::Kept(i)) if self.entries[i].doc.is_none() => {
+                self.entries[i].doc = Some(doc);
+                return Inserted::New(i);
+            }
+            // the exact same text twice over, unless there's no text to speak of
+            Some(Seen::Kept(i)) if self.entries[i].signature.is_some() => {
+                let signature = self.entries[i].signature.clone().unwrap();
+                if summary.source != self.entries[i].summary.source {
+                    self.push_duplicate(summary, i, 1.0, signature);
+                }
+                return Inserted::Duplicate(i);
+            }
+            Some(Seen::Kept(_)) => {}
+            Some(Seen::Duplicate(i)) => return Inserted::Duplicate(self.duplicates[i].kept),
+            None => {}
+        }
+
+        self.signatures.remove(&summary.content_hash);
+        self.insert_inner(doc, summary, signature.0)
+    }
+
+    /// Documents that were left out, in the order they were inserted.
+    pub fn duplicates(&self) -> &[Duplicate] {
+        &self.duplicates
+    }
+
+    /// Summary of the document at `index`, which may not have been loaded in this run.
+    pub fn summary(&self, index: usize) -> &DocumentSummary {
+        &self.entries[index].summary
+    }
+
+    /// Groups each kept document with its duplicates, for the ones that have any.
+    pub fn clusters(&self) -> Vec<DuplicateCluster<'_>> {
+        let mut by_kept: HashMap<usize, Vec<&Duplicate>> = HashMap::new();
+        for duplicate in &self.duplicates {
+            by_kept.entry(duplicate.kept).or_default().push(duplicate);
+        }
+        let mut clusters: Vec<_> = by_kept.into_iter().collect();
+        clusters.sort_by_key(|(kept, _)| *kept);
+
+        clusters
+            .into_iter()
+            .map(|(kept, duplicates)| {
+                let entry = &self.entries[kept];
+                let signatures: Vec<&[u64]> = iter::once(entry.signature.as_deref().unwrap())
+                    .chain(duplicates.iter().map(|d| d.signature.as_slice()))
+                    .collect();
+                let similarities = signatures
+                    .iter()
+                    .map(|a| {
+                        signatures
+                            .iter()
+                            .map(|b| minhash_jaccard_similarity(a, b))
+                            .collect()
+                    })
+                    .collect();
+                DuplicateCluster {
+                    kept: &entry.summary,
+                    duplicates,
+                    similarities,
+                }
+            })
+            .collect()
+    }
+
+    /// The documents in the set which have been inserted in this run.
     pub fn docs(&self) -> impl Iterator<Item = &Document> {
-        self._docs.iter().map(|d| &d.0)
+        self.entries.iter().filter_map(|entry| entry.doc.as_ref())
+    }
+
+    pub fn docs_mut(&mut self) -> impl Iterator<Item = &mut Document> {
+        self.entries
+            .iter_mut()
+            .filter_map(|entry| entry.doc.as_mut())
     }
 
     pub fn into_docs(self) -> impl Iterator<Item = Document> {
-        self._docs.into_iter().map(|d| d.0)
+        self.entries.into_iter().filter_map(|entry| entry.doc)
     }
 }
 
+/// Panics if the document at `index` hasn't been inserted in this run.
 impl std::ops::Index<usize> for DocumentDedupSet {
     type Output = Document;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self._docs[index].0
+        self.entries[index]
+            .doc
+            .as_ref()
+            .expect("document was not inserted in this run")
     }
 }
 
 impl std::ops::IndexMut<usize> for DocumentDedupSet {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self._docs[index].0
+        self.entries[index]
+            .doc
+            .as_mut()
+            .expect("document was not inserted in this run")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subs::SubtitleChunk;
+    use std::time::Duration;
+
+    fn doc(text: &str) -> Document {
+        Document::new(
+            text.lines()
+                .map(|line| DocumentChunk::Plaintext(line.to_string()))
+                .collect(),
+        )
+    }
+
+    fn tokenized(tokens: &[&str]) -> Document {
+        let mut doc = doc(&tokens.concat());
+        let tokenization = vec![tokens.iter().map(|t| t.to_string()).collect()];
+        doc.restore(Some(DocumentTokenization { tokenization }), None);
+        doc
+    }
+
+    #[test]
+    fn short_documents_are_not_all_duplicates() {
+        let mut set = DocumentDedupSet::new();
+        assert!(set.insert_tokenized(tokenized(&[])).kept().is_some());
+        assert!(set.insert_tokenized(tokenized(&[])).kept().is_some());
+        assert!(set.insert_tokenized(tokenized(&["はい"])).kept().is_some());
+        assert!(set
+            .insert_tokenized(tokenized(&["いいえ"]))
+            .kept()
+            .is_some());
+        assert!(set
+            .insert_tokenized(tokenized(&["はい", "。"]))
+            .kept()
+            .is_some());
+        assert!(set.insert_tokenized(tokenized(&["はい"])).kept().is_none());
+    }
+
+    #[test]
+    fn minhash_estimates_jaccard_similarity() {
+        let shingles = |range: std::ops::Range<u64>| -> Vec<u64> {
+            range.map(|i| fxhash::hash64(&i)).collect()
+        };
+        assert_eq!(minhash(&[], 200), None);
+
+        // order and repeats don't matter, it's a set
+        let a = shingles(0..1000);
+        let mut shuffled = a.clone();
+        shuffled.reverse();
+        shuffled.extend_from_slice(&a[..100]);
+        let signature = minhash(&a, 200).unwrap();
+        assert_eq!(signature.len(), 200);
+        assert_eq!(minhash(&shuffled, 200).unwrap(), signature);
+
+        // 500 shared out of 1500, a Jaccard similarity of 1/3
+        let b = minhash(&shingles(500..1500), 200).unwrap();
+        let similarity = minhash_jaccard_similarity(&signature, &b);
+        assert!((similarity - 1.0 / 3.0).abs() < 0.1, "{similarity}");
+        let disjoint = minhash(&shingles(1000..2000), 200).unwrap();
+        assert!(minhash_jaccard_similarity(&signature, &disjoint) < 0.05);
+    }
+
+    #[test]
+    fn shingles_by_characters() {
+        let episode = "ボールを取ってください\n赤い手紙を大事にしてね\nまた明日学校で会おう";
+        let mut set = DocumentDedupSet::with_config(DedupConfig {
+            shingling: Shingling::Characters(4),
+            ..Default::default()
+        });
+        assert!(set.insert_tokenized(doc(episode)).kept().is_some());
+        assert!(set
+            .insert_tokenized(doc(&episode.replace('\n', " \n")))
+            .kept()
+            .is_none());
+        assert!(set
+            .insert_tokenized(doc("全然違う話だよ\nまったく別の内容"))
+            .kept()
+            .is_some());
+
+        let clusters = set.clusters();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].duplicates.len(), 1);
+        assert_eq!(clusters[0].similarities[0][0], 1.0);
+        assert!(clusters[0].similarities[0][1] > 0.8);
+    }
+
+    fn release(name: &str, lines: &[&str]) -> Document {
+        let chunks = lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                DocumentChunk::Subs(SubtitleChunk {
+                    start: Duration::from_secs(i as u64),
+                    end: Duration::from_secs(i as u64 + 1),
+                    content: line.to_string(),
+                })
+            })
+            .collect();
+        Document::new_with_source(chunks, PathBuf::from(name))
+    }
+
+    #[test]
+    fn keeps_the_better_release() {
+        let lines = [
+            "ボールを取ってください",
+            "赤い手紙を大事にしてね",
+            "また明日学校で会おう",
+            "今日はいい天気ですね",
+            "先生に聞いてみよう",
+            "そろそろ帰ろうか",
+        ];
+        let config = DedupConfig {
+            shingling: Shingling::Characters(4),
+            threshold: 0.5,
+            ..Default::default()
+        };
+
+        let mut set = DocumentDedupSet::with_config(config);
+        set.insert_tokenized(release("[A] Show - 01.srt", &lines[..5]));
+        let inserted = set.insert_tokenized(release("[B] Show - 01.srt", &lines));
+        assert_eq!(inserted, Inserted::Replaced(0));
+        assert_eq!(set[0].source(), Some(Path::new("[B] Show - 01.srt")));
+        assert_eq!(
+            set.duplicates()[0].summary.source.as_deref(),
+            Some(Path::new("[A] Show - 01.srt"))
+        );
+
+        let mut set = DocumentDedupSet::with_config(config);
+        set.set_policy(QualityPolicy {
+            release_groups: vec!["A".into()],
+            formats: vec![],
+        });
+        set.insert_tokenized(release("[A] Show - 01.srt", &lines[..5]));
+        let inserted = set.insert_tokenized(release("[B] Show - 01.srt", &lines));
+        assert_eq!(inserted, Inserted::Duplicate(0));
+
+        let mut set = DocumentDedupSet::with_config(config);
+        set.set_policy(FirstWins);
+        set.insert_tokenized(release("[A] Show - 01.srt", &lines[..5]));
+        let inserted = set.insert_tokenized(release("[B] Show - 01.srt", &lines));
+        assert_eq!(inserted, Inserted::Duplicate(0));
+    }
+
+    #[test]
+    fn picks_up_where_it_left_off() {
+        let a = ["ボール", "を", "取っ", "て", "ください", "ね"];
+        let b = ["赤い", "手紙", "を", "大事", "に", "して", "ね"];
+        let path =
+            std::env::temp_dir().join(format!("omoide-dedup-test-{}.bin", std::process::id()));
+
+        let mut set = DocumentDedupSet::new();
+        set.insert_tokenized(tokenized(&a));
+        set.insert_tokenized(tokenized(&b));
+        set.save(&path).unwrap();
+
+        // nothing has to be tokenized again for files already seen
+        let present = [doc(&a.concat()), doc(&b.concat())]
+            .iter()
+            .map(Document::content_hash)
+            .collect();
+        let mut set = DocumentDedupSet::new();
+        set.load(&path, &present);
+        assert!(!set.needs_tokenization(&doc(&b.concat())));
+        assert_eq!(set.insert_tokenized(doc(&b.concat())), Inserted::New(1));
+        assert_eq!(set.insert_tokenized(doc(&a.concat())), Inserted::New(0));
+        assert_eq!(set.docs().count(), 2);
+
+        // files that are gone are forgotten
+        let present = [doc(&b.concat())]
+            .iter()
+            .map(Document::content_hash)
+            .collect();
+        let mut set = DocumentDedupSet::new();
+        set.load(&path, &present);
+        assert!(set.needs_tokenization(&doc(&a.concat())));
+        assert_eq!(set.insert_tokenized(doc(&b.concat())), Inserted::New(0));
+
+        // and nothing is reused under different settings
+        let mut set = DocumentDedupSet::new();
+        set.set_policy(FirstWins);
+        set.load(&path, &present);
+        assert!(set.needs_tokenization(&doc(&b.concat())));
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -1,20 +1,428 @@
-use std::collections::HashMap;
+use std::path::PathBuf;
 
+use anyhow::Context;
+use jmdict::{Enum, GlossLanguage, GlossType, PartOfSpeech, Priority, PriorityInCorpus};
+use jmdict_enums::EnumPayload;
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 
-lazy_static! {
-    pub static ref INDEX_BY_READING: HashMap<String, Vec<jmdict::Entry>> = {
-        let mut map = HashMap::new();
-
-        for entry in jmdict::entries() {
-            let readings = entry
-                .kanji_elements()
-                .map(|el| el.text)
-                .chain(entry.reading_elements().map(|el| el.text));
-            for reading in readings {
-                map.entry(reading.into()).or_insert(vec![]).push(entry);
+use crate::{
+    data,
+    prebuilt::{self, Records, Table, TableBuilder},
+};
+
+/// Enums from `jmdict` don't implement serde, so they're stored as the number the crate uses
+/// for them internally.
+mod payload {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<T: EnumPayload, S: Serializer>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+        value.to_u32().serialize(s)
+    }
+
+    pub fn deserialize<'de, T: EnumPayload, D: Deserializer<'de>>(d: D) -> Result<T, D::Error> {
+        u32::deserialize(d).map(T::from_u32)
+    }
+}
+
+mod payloads {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<T: EnumPayload, S: Serializer>(values: &[T], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(values.iter().map(EnumPayload::to_u32))
+    }
+
+    pub fn deserialize<'de, T: EnumPayload, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<T>, D::Error> {
+        Vec::<u32>::deserialize(d).map(|codes| codes.into_iter().map(T::from_u32).collect())
+    }
+}
+
+/// A JMdict entry, with just what we use of it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// The entry's sequence number in JMdict.
+    pub number: u32,
+    kanji_elements: Vec<KanjiElement>,
+    reading_elements: Vec<ReadingElement>,
+    senses: Vec<Sense>,
+}
+
+impl Entry {
+    pub fn kanji_elements(&self) -> impl Iterator<Item = &KanjiElement> {
+        self.kanji_elements.iter()
+    }
+
+    pub fn reading_elements(&self) -> impl Iterator<Item = &ReadingElement> {
+        self.reading_elements.iter()
+    }
+
+    pub fn senses(&self) -> impl Iterator<Item = &Sense> {
+        self.senses.iter()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KanjiElement {
+    pub text: String,
+    #[serde(with = "payload")]
+    pub priority: Priority,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadingElement {
+    pub text: String,
+    #[serde(with = "payload")]
+    pub priority: Priority,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sense {
+    #[serde(with = "payloads")]
+    parts_of_speech: Vec<PartOfSpeech>,
+    glosses: Vec<Gloss>,
+}
+
+impl Sense {
+    pub fn parts_of_speech(&self) -> impl Iterator<Item = PartOfSpeech> + '_ {
+        self.parts_of_speech.iter().copied()
+    }
+
+    pub fn glosses(&self) -> impl Iterator<Item = &Gloss> {
+        self.glosses.iter()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Gloss {
+    pub text: String,
+    #[serde(with = "payload")]
+    pub language: GlossLanguage,
+    #[serde(with = "payload")]
+    pub gloss_type: GlossType,
+}
+
+/// Reads priority codes like `news1` or `nf12` into what they say about a word.
+fn parse_priority<'a>(codes: impl Iterator<Item = &'a str>) -> Priority {
+    let mut priority = Priority::default();
+    for code in codes {
+        let (corpus, rank) = code.split_at(code.len().saturating_sub(1));
+        let rank = match rank {
+            "1" => PriorityInCorpus::Primary,
+            "2" => PriorityInCorpus::Secondary,
+            _ => PriorityInCorpus::Absent,
+        };
+        // contradictory codes are resolved in favour of the higher priority
+        let keep_higher = |current: &mut PriorityInCorpus| {
+            if *current != PriorityInCorpus::Primary {
+                *current = rank;
+            }
+        };
+        match corpus {
+            "news" => keep_higher(&mut priority.news),
+            "ichi" => keep_higher(&mut priority.ichimango),
+            "gai" => keep_higher(&mut priority.loanwords),
+            "spec" => keep_higher(&mut priority.additional),
+            _ => {
+                if let Some(bucket) = code.strip_prefix("nf").and_then(|n| n.parse().ok()) {
+                    priority.frequency_bucket = bucket;
+                }
+            }
+        }
+    }
+    priority
+}
+
+/// JMdict uses XML entities for its codes, e.g. `&v5r;`, which it declares in its DTD. There's
+/// no point in expanding them to their descriptions only to look them up again, so they're
+/// replaced with their names.
+fn replace_entities(xml: &str) -> String {
+    lazy_static! {
+        static ref ENTITY_RE: regex::Regex = regex::Regex::new(r"&([\w-]+);").unwrap();
+    }
+    ENTITY_RE
+        .replace_all(xml, |caps: &regex::Captures| match &caps[1] {
+            "amp" | "lt" | "gt" | "quot" | "apos" => caps[0].to_string(),
+            code => code.to_string(),
+        })
+        .into_owned()
+}
+
+/// Parses a JMdict XML release, returning its entries and when it was created, if it says.
+pub fn parse_jmdict(xml: &str) -> anyhow::Result<(Vec<Entry>, Option<String>)> {
+    let created = xml.find("<!-- JMdict created:").and_then(|start| {
+        let comment = &xml[start + "<!-- JMdict created:".len()..];
+        comment
+            .find("-->")
+            .map(|end| comment[..end].trim().to_string())
+    });
+    // roxmltree doesn't do entities declared in the DTD, so skip it
+    let start = xml.find("<JMdict>").context("No <JMdict> element")?;
+    let xml = replace_entities(&xml[start..]);
+    let document = roxmltree::Document::parse(&xml)?;
+
+    let texts = |node: roxmltree::Node, name: &str| -> Vec<String> {
+        node.children()
+            .filter(|child| child.has_tag_name(name))
+            .filter_map(|child| child.text().map(String::from))
+            .collect()
+    };
+    let mut entries = vec![];
+    for node in document.root_element().children() {
+        if !node.has_tag_name("entry") {
+            continue;
+        }
+        let number = texts(node, "ent_seq")
+            .first()
+            .and_then(|seq| seq.parse().ok())
+            .context("Entry without a sequence number")?;
+        let element = |el: roxmltree::Node, text: &str| -> Option<(String, Priority)> {
+            let text = texts(el, text).into_iter().next()?;
+            let priority = texts(
+                el,
+                if el.has_tag_name("k_ele") {
+                    "ke_pri"
+                } else {
+                    "re_pri"
+                },
+            );
+            Some((text, parse_priority(priority.iter().map(String::as_str))))
+        };
+        let mut entry = Entry {
+            number,
+            kanji_elements: vec![],
+            reading_elements: vec![],
+            senses: vec![],
+        };
+        // parts of speech carry over to the senses that follow until they're given again
+        let mut parts_of_speech = vec![];
+        for child in node.children() {
+            match child.tag_name().name() {
+                "k_ele" => {
+                    if let Some((text, priority)) = element(child, "keb") {
+                        entry.kanji_elements.push(KanjiElement { text, priority });
+                    }
+                }
+                "r_ele" => {
+                    if let Some((text, priority)) = element(child, "reb") {
+                        entry
+                            .reading_elements
+                            .push(ReadingElement { text, priority });
+                    }
+                }
+                "sense" => {
+                    let pos = texts(child, "pos");
+                    if !pos.is_empty() {
+                        parts_of_speech = pos
+                            .iter()
+                            .filter_map(|code| PartOfSpeech::from_code(code))
+                            .collect();
+                    }
+                    let glosses = child
+                        .children()
+                        .filter(|gloss| gloss.has_tag_name("gloss"))
+                        .filter_map(|gloss| {
+                            let language = gloss
+                                .attribute((roxmltree::NS_XML_URI, "lang"))
+                                .unwrap_or("eng");
+                            Some(Gloss {
+                                text: gloss.text()?.into(),
+                                language: GlossLanguage::from_code(language)?,
+                                gloss_type: GlossType::from_code(
+                                    gloss.attribute("g_type").unwrap_or(""),
+                                )
+                                .unwrap_or(GlossType::RegularTranslation),
+                            })
+                        })
+                        .collect();
+                    entry.senses.push(Sense {
+                        parts_of_speech: parts_of_speech.clone(),
+                        glosses,
+                    });
+                }
+                _ => {}
             }
         }
-        map
+        entries.push(entry);
+    }
+    Ok((entries, created))
+}
+
+/// The JMdict in the data directory, if one's been imported or put there.
+fn jmdict_source() -> Option<PathBuf> {
+    let imported = data::data_dir().join(data::JMDICT_FILE);
+    if imported.is_file() {
+        Some(imported)
+    } else {
+        data::find_xml(data::JMDICT_XML_FILES)
+    }
+}
+
+fn load_jmdict() -> Vec<Entry> {
+    if let Some(entries) = data::load(data::JMDICT_FILE) {
+        return entries;
+    }
+    let Some(path) = data::find_xml(data::JMDICT_XML_FILES) else {
+        eprintln!(
+            "No JMdict in '{}', import it with `omoide manage --import-jmdict <PATH>`",
+            data::data_dir().display()
+        );
+        return vec![];
     };
+    match data::read_xml(&path).and_then(|xml| parse_jmdict(&xml)) {
+        Ok((entries, _)) => entries,
+        Err(e) => {
+            eprintln!("Couldn't read '{}': {e:#}", path.display());
+            vec![]
+        }
+    }
+}
+
+/// Indexes entries by every way of writing them, leaving the entries themselves out if we
+/// already have them in memory.
+fn build_table(entries: &[Entry], with_entries: bool, stamp: u64) -> anyhow::Result<Vec<u8>> {
+    let mut builder = TableBuilder::new();
+    for entry in entries {
+        let record = match with_entries {
+            true => builder.push(&bincode::serialize(entry)?),
+            false => builder.push(&[]),
+        };
+        let spellings = entry
+            .kanji_elements()
+            .map(|el| &el.text)
+            .chain(entry.reading_elements().map(|el| &el.text));
+        for spelling in spellings {
+            builder.key(spelling, record);
+        }
+    }
+    builder.finish(stamp)
+}
+
+/// Prebuilds JMdict into the data directory, so it doesn't have to be loaded and indexed every
+/// time. Returns the number of entries.
+pub fn build_index() -> anyhow::Result<usize> {
+    let source = jmdict_source().with_context(|| {
+        format!(
+            "No JMdict in '{}', import it with `omoide manage --import-jmdict <PATH>`",
+            data::data_dir().display()
+        )
+    })?;
+    let stamp = prebuilt::stamp(Some(&source));
+    let entries = load_jmdict();
+    let table = build_table(&entries, true, stamp)?;
+    data::write_atomically(&data::data_dir().join(data::JMDICT_INDEX_FILE), &table)?;
+    Ok(entries.len())
+}
+
+fn open_jmdict() -> Records<Entry> {
+    let path = data::data_dir().join(data::JMDICT_INDEX_FILE);
+    if path.is_file() {
+        match Table::open(&path, prebuilt::stamp(jmdict_source().as_deref())) {
+            Ok(table) => return Records::new(table, |entry| Ok(bincode::deserialize(entry)?)),
+            Err(e) => eprintln!("{e:#}, rebuild it with `omoide manage --build-index`"),
+        }
+    }
+    let entries = load_jmdict();
+    let table = build_table(&entries, false, 0)
+        .and_then(Table::from_bytes)
+        .expect("couldn't index JMdict");
+    Records::with_decoded(table, entries)
+}
+
+lazy_static! {
+    /// JMdict, from the data directory if it's been imported there.
+    static ref JMDICT: Records<Entry> = open_jmdict();
+}
+
+/// Entries written as `word`, in kanji or kana.
+pub fn lookup(word: &str) -> Vec<&'static Entry> {
+    JMDICT.lookup(word).collect()
+}
+
+/// Whether JMdict marks `word` as common, in any of its spellings.
+pub fn is_common(word: &str) -> bool {
+    JMDICT.lookup(word).any(|entry| {
+        entry
+            .kanji_elements()
+            .filter(|el| el.text == word)
+            .map(|el| el.priority)
+            .chain(
+                entry
+                    .reading_elements()
+                    .filter(|el| el.text == word)
+                    .map(|el| el.priority),
+            )
+            .any(|priority| priority.is_common())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jmdict_xml_parses() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE JMdict [
+<!ENTITY v5r "Godan verb with 'ru' ending">
+<!ENTITY vi "intransitive verb">
+<!ENTITY n "noun (common) (futsuumeishi)">
+]>
+<!-- JMdict created: 2024-05-01 -->
+<JMdict>
+<entry>
+<ent_seq>1454500</ent_seq>
+<k_ele><keb>登る</keb><ke_pri>ichi1</ke_pri><ke_pri>news2</ke_pri><ke_pri>nf30</ke_pri></k_ele>
+<r_ele><reb>のぼる</reb><re_pri>ichi1</re_pri></r_ele>
+<sense><pos>&v5r;</pos><pos>&vi;</pos><gloss>to ascend</gloss><gloss g_type="lit">to go up</gloss></sense>
+<sense><gloss>to climb</gloss><gloss xml:lang="ger">steigen</gloss></sense>
+</entry>
+<entry>
+<ent_seq>1000000</ent_seq>
+<r_ele><reb>ヽ</reb></r_ele>
+<sense><pos>&n;</pos><gloss>repetition mark &amp; the like</gloss></sense>
+</entry>
+</JMdict>"#;
+        let (entries, created) = parse_jmdict(xml).unwrap();
+        assert_eq!(created.as_deref(), Some("2024-05-01"));
+        assert_eq!(entries.len(), 2);
+
+        let noboru = &entries[0];
+        assert_eq!(noboru.number, 1454500);
+        let kanji = noboru.kanji_elements().next().unwrap();
+        assert_eq!(kanji.text, "登る");
+        assert_eq!(kanji.priority.ichimango, PriorityInCorpus::Primary);
+        assert_eq!(kanji.priority.news, PriorityInCorpus::Secondary);
+        assert_eq!(kanji.priority.frequency_bucket, 30);
+        assert!(kanji.priority.is_common());
+        assert_eq!(noboru.reading_elements().next().unwrap().text, "のぼる");
+
+        let senses: Vec<_> = noboru.senses().collect();
+        assert_eq!(senses.len(), 2);
+        // the second sense takes its parts of speech from the first
+        for sense in &senses {
+            assert_eq!(
+                sense.parts_of_speech().collect::<Vec<_>>(),
+                [PartOfSpeech::GodanRuVerb, PartOfSpeech::IntransitiveVerb]
+            );
+        }
+        let glosses: Vec<_> = senses[0].glosses().collect();
+        assert_eq!(glosses[1].text, "to go up");
+        assert_eq!(glosses[1].gloss_type, GlossType::LiteralTranslation);
+        assert_eq!(glosses[0].gloss_type, GlossType::RegularTranslation);
+        let languages: Vec<_> = senses[1].glosses().map(|gloss| gloss.language).collect();
+        assert_eq!(languages, [GlossLanguage::English, GlossLanguage::German]);
+
+        let gloss = entries[1]
+            .senses()
+            .next()
+            .unwrap()
+            .glosses()
+            .next()
+            .unwrap();
+        assert_eq!(gloss.text, "repetition mark & the like");
+    }
 }
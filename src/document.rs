@@ -1,6 +1,7 @@
 use std::path::{Path, PathBuf};
 
 use crate::{
+    metadata::EpisodeMetadata,
     nlp::{Analysis, DocumentTokenization, Engine},
     subs::SubtitleChunk,
 };
@@ -8,6 +9,7 @@ use crate::{
 pub struct Document {
     _chunks: Vec<DocumentChunk>,
     _source: Option<PathBuf>,
+    _metadata: EpisodeMetadata,
     _tokenization: Option<DocumentTokenization>,
     _analysis: Option<Vec<Analysis>>,
 }
@@ -37,6 +39,7 @@ impl Document {
         Self {
             _chunks: contents,
             _source: None,
+            _metadata: EpisodeMetadata::default(),
             _tokenization: None,
             _analysis: None,
         }
@@ -45,6 +48,7 @@ impl Document {
     pub fn new_with_source(contents: Vec<DocumentChunk>, source: PathBuf) -> Self {
         Self {
             _chunks: contents,
+            _metadata: EpisodeMetadata::from_path(&source),
             _source: Some(source),
             _tokenization: None,
             _analysis: None,
@@ -83,7 +87,38 @@ impl Document {
         Ok(self.analysis().unwrap())
     }
 
+    /// Hash of the document's text, which determines what the NLP engine makes of it.
+    pub fn content_hash(&self) -> u64 {
+        use std::hash::Hasher;
+
+        let mut hasher = fxhash::FxHasher64::default();
+        for chunk in self.contents() {
+            hasher.write(chunk.as_bytes());
+            hasher.write_u8(0xff); // not valid UTF-8, so chunks can't run into each other
+        }
+        hasher.finish()
+    }
+
+    /// Fills in NLP results computed elsewhere, usually in an earlier run, see `AnalysisCache`.
+    pub fn restore(
+        &mut self,
+        tokenization: Option<DocumentTokenization>,
+        analysis: Option<Vec<Analysis>>,
+    ) {
+        if self._tokenization.is_none() {
+            self._tokenization = tokenization;
+        }
+        if self._analysis.is_none() {
+            self._analysis = analysis;
+        }
+    }
+
     pub fn source(&self) -> Option<&Path> {
         self._source.as_ref().map(PathBuf::as_path)
     }
+
+    /// Show and episode information, as far as we could guess it from the source file name.
+    pub fn metadata(&self) -> &EpisodeMetadata {
+        &self._metadata
+    }
 }
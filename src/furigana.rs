@@ -0,0 +1,1037 @@
+//! Lining up the reading of a word with the kanji it's written with, so each kanji gets its own
+//! furigana rather than the word getting the reading as a whole.
+//!
+//! Kanji don't always keep the reading a dictionary gives them, so on top of those it tries:
+//! - rendaku, where the first kana is voiced, like 三杯 (さん・ばい) or 人々 (ひと・びと)
+//! - sokuon, where a final つ, く, ち or き becomes っ, like 学校 (がっ・こう)
+//! - a final ち or つ left out altogether, like 日本 (に・ほん)
+//! - long vowels shortened, like 女房 (にょ・ぼう) from にょう
+//! - kun readings with some of their okurigana, which is often left out, like 受付 (うけ・つけ)
+//!
+//! Runs of kanji that still can't be split up, like 今日 (きょう), get one reading together.
+//!
+//! Words that aren't in the dictionary at all, like most names, get a reading guessed from their
+//! kanji, which is marked as such since it's often wrong.
+//!
+//! A `FuriganaPolicy` can then leave out the furigana a learner doesn't need.
+use std::collections::HashSet;
+
+use serde::Serialize;
+use wana_kana::to_hiragana::to_hiragana;
+
+use crate::{
+    args::FuriganaArgs,
+    kanji::{is_kanji, KanjiLevel, ITERATION_MARK},
+    known::KnownWords,
+};
+
+/// Backtracking is exponential in the worst case, so it's given up on after this many steps.
+const MAX_STEPS: usize = 10_000;
+
+/// Part of a word, with its reading if it's written in kanji.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub text: String,
+    pub reading: Option<String>,
+    /// Whether the reading was guessed from the kanji rather than found in the dictionary.
+    pub guessed: bool,
+}
+
+impl Segment {
+    /// The whole word with the whole reading, for when it can't be lined up any better.
+    pub fn whole(text: &str, reading: &str) -> Self {
+        Self {
+            text: text.into(),
+            reading: Some(to_hiragana(reading)),
+            guessed: false,
+        }
+    }
+
+    /// Text without a reading.
+    pub fn bare(text: &str) -> Self {
+        Self {
+            text: text.into(),
+            reading: None,
+            guessed: false,
+        }
+    }
+}
+
+fn voiced(c: char) -> &'static [char] {
+    match c {
+        'か' => &['が'],
+        'き' => &['ぎ'],
+        'く' => &['ぐ'],
+        'け' => &['げ'],
+        'こ' => &['ご'],
+        'さ' => &['ざ'],
+        'し' => &['じ'],
+        'す' => &['ず'],
+        'せ' => &['ぜ'],
+        'そ' => &['ぞ'],
+        'た' => &['だ'],
+        'ち' => &['ぢ', 'じ'],
+        'つ' => &['づ', 'ず'],
+        'て' => &['で'],
+        'と' => &['ど'],
+        'は' => &['ば', 'ぱ'],
+        'ひ' => &['び', 'ぴ'],
+        'ふ' => &['ぶ', 'ぷ'],
+        'へ' => &['べ', 'ぺ'],
+        'ほ' => &['ぼ', 'ぽ'],
+        _ => &[],
+    }
+}
+
+/// The vowel a kana ends in, as the kana that lengthens it.
+fn vowel(c: char) -> Option<char> {
+    const ROWS: [(&str, char); 5] = [
+        ("あかがさざただなはばぱまやゃらわ", 'あ'),
+        ("いきぎしじちぢにひびぴみり", 'い'),
+        ("うくぐすずつづぬふぶぷむゆゅる", 'う'),
+        ("えけげせぜてでねへべぺめれ", 'え'),
+        ("おこごそぞとどのほぼぽもよょろを", 'お'),
+    ];
+    ROWS.iter()
+        .find(|(row, _)| row.contains(c))
+        .map(|&(_, vowel)| vowel)
+}
+
+/// Whether `vowel` lengthens the kana before it, as う does in こう and い in せい.
+fn lengthens(before: char, vowel_kana: char) -> bool {
+    matches!(
+        (vowel(before), vowel_kana),
+        (Some('お' | 'う'), 'う') | (Some('え' | 'い'), 'い') | (Some(_), 'ー')
+    )
+}
+
+/// The ways a reading might be written as part of a word, the reading as it is first.
+fn variants(reading: &str) -> Vec<Vec<char>> {
+    let reading: Vec<char> = reading.chars().collect();
+    let mut variants = vec![reading.clone()];
+    if let Some(&first) = reading.first() {
+        for &voiced in voiced(first) {
+            let mut variant = reading.clone();
+            variant[0] = voiced;
+            variants.push(variant);
+        }
+    }
+    for i in 0..variants.len() {
+        let variant = variants[i].clone();
+        if variant.len() >= 2 && matches!(variant.last(), Some('つ' | 'く' | 'ち' | 'き')) {
+            let mut geminated = variant.clone();
+            *geminated.last_mut().unwrap() = 'っ';
+            variants.push(geminated);
+        }
+        if variant.len() >= 2 && matches!(variant.last(), Some('つ' | 'ち')) {
+            variants.push(variant[..variant.len() - 1].to_vec());
+        }
+        if let [.., before, last] = variant[..] {
+            if lengthens(before, last) {
+                variants.push(variant[..variant.len() - 1].to_vec());
+            }
+        }
+    }
+    variants
+}
+
+/// Whether kana in the word matches kana in its reading, which might be in the other script or
+/// spell a long vowel out.
+fn kana_matches(written: char, read: char, read_before: Option<char>) -> bool {
+    let written = to_hiragana(written.encode_utf8(&mut [0; 4]));
+    let read_as = to_hiragana(read.encode_utf8(&mut [0; 4]));
+    written == read_as
+        || (written == "ー" && read_before.is_some_and(|before| lengthens(before, read)))
+}
+
+struct Aligner {
+    text: Vec<char>,
+    reading: Vec<char>,
+    /// Whether each character of the text is a kanji.
+    is_kanji: Vec<bool>,
+    /// What each kanji might be read as, most likely first. Empty for kana, and for kanji the
+    /// dictionary has no readings for.
+    candidates: Vec<Vec<Vec<char>>>,
+    steps: usize,
+}
+
+impl Aligner {
+    fn matches_kana(&self, ti: usize, ri: usize) -> bool {
+        ri < self.reading.len()
+            && kana_matches(
+                self.text[ti],
+                self.reading[ri],
+                ri.checked_sub(1).map(|before| self.reading[before]),
+            )
+    }
+
+    /// Lines up the text from `ti` with the reading from `ri`, a kanji at a time, pushing where
+    /// each kanji's reading ends in the reading.
+    fn per_kanji(&mut self, ti: usize, ri: usize, ends: &mut Vec<usize>) -> bool {
+        if ti == self.text.len() {
+            return ri == self.reading.len();
+        }
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return false;
+        }
+        if !self.is_kanji[ti] {
+            ends.push(ri + 1);
+            if self.matches_kana(ti, ri) && self.per_kanji(ti + 1, ri + 1, ends) {
+                return true;
+            }
+            ends.pop();
+            return false;
+        }
+        if self.candidates[ti].is_empty() {
+            // no telling how it's read, so it can take any of the reading
+            for end in ri + 1..=self.reading.len() {
+                ends.push(end);
+                if self.per_kanji(ti + 1, end, ends) {
+                    return true;
+                }
+                ends.pop();
+            }
+            return false;
+        }
+        for c in 0..self.candidates[ti].len() {
+            let candidate = &self.candidates[ti][c];
+            let end = ri + candidate.len();
+            if !self.reading[ri..].starts_with(candidate) {
+                continue;
+            }
+            ends.push(end);
+            if self.per_kanji(ti + 1, end, ends) {
+                return true;
+            }
+            ends.pop();
+        }
+        false
+    }
+
+    /// Lines up runs of kanji with whatever's between the kana around them, pushing where each
+    /// run's reading ends. `runs` are where each run of kanji or of kana starts and ends, and
+    /// whether it's kanji.
+    fn per_run(&mut self, runs: &[(usize, usize, bool)], ri: usize, ends: &mut Vec<usize>) -> bool {
+        let Some(&(start, end, is_kanji)) = runs.first() else {
+            return ri == self.reading.len();
+        };
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return false;
+        }
+        if !is_kanji {
+            let matched = (start..end).all(|ti| self.matches_kana(ti, ri + ti - start));
+            let reading_end = ri + end - start;
+            ends.push(reading_end);
+            if matched && self.per_run(&runs[1..], reading_end, ends) {
+                return true;
+            }
+            ends.pop();
+            return false;
+        }
+        for reading_end in ri + 1..=self.reading.len() {
+            ends.push(reading_end);
+            if self.per_run(&runs[1..], reading_end, ends) {
+                return true;
+            }
+            ends.pop();
+        }
+        false
+    }
+}
+
+/// Merges neighbouring kana into one segment.
+fn push_segment(segments: &mut Vec<Segment>, text: &[char], reading: Option<&[char]>) {
+    let text: String = text.iter().collect();
+    match (reading, segments.last_mut()) {
+        (None, Some(last)) if last.reading.is_none() => last.text.push_str(&text),
+        _ => segments.push(Segment {
+            text,
+            reading: reading.map(|reading| reading.iter().collect()),
+            guessed: false,
+        }),
+    }
+}
+
+/// Splits `text` into kanji with their readings and the kana around them, given its
+/// `reading`, and the readings `readings_of` a kanji has in a dictionary. Returns `None` if the
+/// reading can't be lined up with the text at all.
+pub fn align(
+    text: &str,
+    reading: &str,
+    readings_of: impl Fn(char) -> Vec<String>,
+) -> Option<Vec<Segment>> {
+    let text: Vec<char> = text.chars().collect();
+    let reading: Vec<char> = to_hiragana(reading).chars().collect();
+    let kanji_at: Vec<bool> = text.iter().map(|&c| is_kanji(c)).collect();
+    let mut candidates = vec![];
+    for (i, &c) in text.iter().enumerate() {
+        if !kanji_at[i] {
+            candidates.push(vec![]);
+            continue;
+        }
+        let repeated = text[..i]
+            .iter()
+            .rev()
+            .find(|&&before| before != ITERATION_MARK);
+        let kanji = match (c, repeated) {
+            (ITERATION_MARK, Some(&before)) => before,
+            _ => c,
+        };
+        let mut readings = readings_of(kanji);
+        readings.sort_by_key(|reading| std::cmp::Reverse(reading.chars().count()));
+        let mut kanji_candidates: Vec<Vec<char>> = vec![];
+        for reading in readings {
+            for variant in variants(&to_hiragana(&reading)) {
+                if !variant.is_empty() && !kanji_candidates.contains(&variant) {
+                    kanji_candidates.push(variant);
+                }
+            }
+        }
+        candidates.push(kanji_candidates);
+    }
+
+    let mut aligner = Aligner {
+        text,
+        reading,
+        is_kanji: kanji_at,
+        candidates,
+        steps: 0,
+    };
+    let mut segments = vec![];
+    let mut ends = vec![];
+    if aligner.per_kanji(0, 0, &mut ends) {
+        let mut start = 0;
+        for (ti, end) in ends.into_iter().enumerate() {
+            let reading = aligner.is_kanji[ti].then(|| &aligner.reading[start..end]);
+            push_segment(&mut segments, &aligner.text[ti..ti + 1], reading);
+            start = end;
+        }
+        return Some(segments);
+    }
+
+    // a kanji read some way the dictionary doesn't have, so make do with runs of them
+    let mut runs: Vec<(usize, usize, bool)> = vec![];
+    for (ti, &is_kanji) in aligner.is_kanji.iter().enumerate() {
+        match runs.last_mut() {
+            Some((_, end, run_is_kanji)) if *run_is_kanji == is_kanji => *end = ti + 1,
+            _ => runs.push((ti, ti + 1, is_kanji)),
+        }
+    }
+    aligner.steps = 0;
+    if !aligner.per_run(&runs, 0, &mut ends) {
+        return None;
+    }
+    let mut start = 0;
+    for (&(text_start, text_end, is_kanji), end) in runs.iter().zip(ends) {
+        let text = &aligner.text[text_start..text_end];
+        let reading = &aligner.reading[start..end];
+        start = end;
+        if !is_kanji {
+            push_segment(&mut segments, text, None);
+            continue;
+        }
+        // the run might still split up on its own, if it's next to the kanji that didn't
+        let text: String = text.iter().collect();
+        let reading: String = reading.iter().collect();
+        let mut run_aligner = Aligner {
+            is_kanji: aligner.is_kanji[text_start..text_end].to_vec(),
+            candidates: aligner.candidates[text_start..text_end].to_vec(),
+            text: text.chars().collect(),
+            reading: reading.chars().collect(),
+            steps: 0,
+        };
+        let mut run_ends = vec![];
+        if run_aligner.per_kanji(0, 0, &mut run_ends) {
+            let mut run_start = 0;
+            for (ti, run_end) in run_ends.into_iter().enumerate() {
+                let run_reading = &run_aligner.reading[run_start..run_end];
+                push_segment(
+                    &mut segments,
+                    &run_aligner.text[ti..ti + 1],
+                    Some(run_reading),
+                );
+                run_start = run_end;
+            }
+        } else {
+            segments.push(Segment {
+                text,
+                reading: Some(reading),
+                guessed: false,
+            });
+        }
+    }
+    Some(segments)
+}
+
+/// What KANJIDIC has for a kanji, to guess the reading of a word from.
+#[derive(Clone, Debug, Default)]
+pub struct KanjiReadings {
+    pub on: Vec<String>,
+    /// Kun readings, with the okurigana that goes with them if there is any.
+    pub kun: Vec<(String, Option<String>)>,
+}
+
+/// Guesses the reading of a word that isn't in the dictionary from the readings its kanji have
+/// in `readings_of`: on readings for kanji in compounds, and kun readings for kanji on their
+/// own, going by the okurigana after them. The segments are marked as guessed. Returns `None` if
+/// a kanji has no readings at all.
+pub fn guess(text: &str, readings_of: impl Fn(char) -> KanjiReadings) -> Option<Vec<Segment>> {
+    let text: Vec<char> = text.chars().collect();
+    let is_compounded = |i: usize| {
+        text.get(i)
+            .is_some_and(|&c| c != ITERATION_MARK && is_kanji(c))
+    };
+    let mut segments = vec![];
+    let mut previous: Option<String> = None;
+    for (i, &c) in text.iter().enumerate() {
+        if !is_kanji(c) {
+            push_segment(&mut segments, &text[i..i + 1], None);
+            continue;
+        }
+        let reading = if c == ITERATION_MARK {
+            // the kanji before again, usually with rendaku as in 人々 (ひとびと)
+            let mut reading: Vec<char> = previous?.chars().collect();
+            if let Some(&voiced) = voiced(reading[0]).first() {
+                reading[0] = voiced;
+            }
+            reading.into_iter().collect()
+        } else {
+            let readings = readings_of(c);
+            let after: String = text[i + 1..]
+                .iter()
+                .take_while(|&&c| !is_kanji(c))
+                .collect();
+            let after = to_hiragana(&after);
+            let on = readings.on.first();
+            let kun_where = |found: &dyn Fn(&Option<String>) -> bool| {
+                readings
+                    .kun
+                    .iter()
+                    .find(|(_, okurigana)| found(okurigana))
+                    .map(|(stem, _)| stem)
+            };
+            let plain_kun = kun_where(&|okurigana| okurigana.is_none());
+            let reading = if i.checked_sub(1).is_some_and(is_compounded) || is_compounded(i + 1) {
+                on.or(plain_kun)
+            } else {
+                kun_where(&|okurigana| {
+                    okurigana
+                        .as_ref()
+                        .is_some_and(|okurigana| after.starts_with(&to_hiragana(okurigana)))
+                })
+                .or(plain_kun)
+                .or(kun_where(&|okurigana| okurigana.is_some()))
+                .or(on)
+            };
+            to_hiragana(reading.or(readings.kun.first().map(|(stem, _)| stem))?)
+        };
+        previous = Some(reading.clone());
+        segments.push(Segment {
+            text: c.into(),
+            reading: Some(reading),
+            guessed: true,
+        });
+    }
+    Some(segments)
+}
+
+/// Which furigana to leave out, for learners who don't need all of it. Keeps track of the words
+/// annotated so far, so it's made anew for each document.
+#[derive(Clone, Debug, Default)]
+pub struct FuriganaPolicy {
+    /// Words to leave unannotated.
+    known: Option<KnownWords>,
+    /// Only kanji taught after this grade get furigana.
+    above_grade: Option<u8>,
+    /// Only kanji harder than this JLPT level get furigana.
+    above_jlpt: Option<u8>,
+    /// Only the first occurrence of a word gets furigana.
+    first_only: bool,
+    /// Lemmas of the words annotated so far.
+    seen: HashSet<String>,
+}
+
+impl FuriganaPolicy {
+    pub fn from_args(args: &FuriganaArgs) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            args.above_grade
+                .is_none_or(|grade| (1..=10).contains(&grade)),
+            "--above-grade has to be from 1 to 10"
+        );
+        anyhow::ensure!(
+            args.above_jlpt.is_none_or(|jlpt| (1..=4).contains(&jlpt)),
+            "--above-jlpt has to be from 1 to 4"
+        );
+        Ok(Self {
+            known: args
+                .known_words
+                .as_deref()
+                .map(KnownWords::load)
+                .transpose()?,
+            above_grade: args.above_grade,
+            above_jlpt: args.above_jlpt,
+            first_only: args.first_only,
+            seen: HashSet::new(),
+        })
+    }
+
+    /// Whether a kanji is advanced enough to get furigana. Kanji KANJIDIC doesn't grade are taken
+    /// to be harder than any it does.
+    fn is_advanced(&self, level: KanjiLevel) -> bool {
+        let above_grade = self
+            .above_grade
+            .is_none_or(|above| level.grade.is_none_or(|grade| grade > above));
+        let above_jlpt = self
+            .above_jlpt
+            .is_none_or(|above| level.jlpt.is_none_or(|jlpt| jlpt < above));
+        above_grade && above_jlpt
+    }
+
+    /// Takes the readings the policy leaves out off `segments`, the furigana of the word `text`
+    /// with the dictionary form `lemma`. `level_of` tells how advanced a kanji is.
+    pub fn apply(
+        &mut self,
+        text: &str,
+        lemma: &str,
+        segments: &mut [Segment],
+        level_of: impl Fn(char) -> KanjiLevel,
+    ) {
+        let known = self
+            .known
+            .as_ref()
+            .is_some_and(|known| known.contains(lemma) || known.contains(text));
+        let mut before = None;
+        for segment in segments.iter_mut() {
+            let mut advanced = false;
+            for c in segment.text.chars().filter(|&c| is_kanji(c)) {
+                let kanji = match (c, before) {
+                    (ITERATION_MARK, Some(before)) => before,
+                    _ => c,
+                };
+                advanced |= self.is_advanced(level_of(kanji));
+                before = Some(kanji);
+            }
+            if known || !advanced {
+                segment.reading = None;
+                segment.guessed = false;
+            }
+        }
+
+        let annotated = segments.iter().any(|segment| segment.reading.is_some());
+        if self.first_only && annotated && !self.seen.insert(lemma.to_string()) {
+            for segment in segments {
+                segment.reading = None;
+                segment.guessed = false;
+            }
+        }
+    }
+}
+
+/// The reading of `surface`, an inflected form of `lemma`, given the reading of the lemma, e.g.
+/// とって for 取って from 取る (とる). Inflection only changes the kana after the last kanji,
+/// so that's swapped for the surface's. Returns `None` if `surface` isn't a form of `lemma`.
+pub fn inflected_reading(lemma: &str, lemma_reading: &str, surface: &str) -> Option<String> {
+    let lemma: Vec<char> = lemma.chars().collect();
+    let reading: Vec<char> = to_hiragana(lemma_reading).chars().collect();
+    // the okurigana, read the same in the lemma and its reading
+    let mut stem_len = lemma.len();
+    let mut stem_reading_len = reading.len();
+    while stem_len > 0 && stem_reading_len > 0 {
+        let c = lemma[stem_len - 1];
+        if is_kanji(c) || !kana_matches(c, reading[stem_reading_len - 1], None) {
+            break;
+        }
+        stem_len -= 1;
+        stem_reading_len -= 1;
+    }
+    let stem: String = lemma[..stem_len].iter().collect();
+    let inflection = surface.strip_prefix(stem.as_str())?;
+    if inflection.chars().any(is_kanji) {
+        return None;
+    }
+
+    let mut stem_reading: String = reading[..stem_reading_len].iter().collect();
+    // 来る is the one verb whose kanji is read differently depending on how it's inflected
+    let lemma: String = lemma.iter().collect();
+    if lemma.ends_with("来る") && stem_reading.ends_with('く') {
+        stem_reading.pop();
+        stem_reading.push(match inflection.chars().next() {
+            Some('な' | 'よ' | 'ら' | 'さ' | 'い') => 'こ',
+            Some('る' | 'れ') | None => 'く',
+            Some(_) => 'き',
+        });
+    }
+    Some(stem_reading + &to_hiragana(inflection))
+}
+
+/// HTML ruby markup for the segments, with neighbouring kanji in the same `<ruby>`. Guessed
+/// readings get `class="guessed"` if `mark_guessed`.
+pub fn ruby_markup(segments: &[Segment], mark_guessed: bool) -> String {
+    let mut markup = String::new();
+    let mut in_ruby = false;
+    for segment in segments {
+        match &segment.reading {
+            Some(reading) => {
+                if !in_ruby {
+                    markup.push_str("<ruby>");
+                    in_ruby = true;
+                }
+                markup.push_str(&segment.text);
+                markup.push_str(if mark_guessed && segment.guessed {
+                    "<rp>(</rp><rt class=\"guessed\">"
+                } else {
+                    "<rp>(</rp><rt>"
+                });
+                markup.push_str(reading);
+                markup.push_str("</rt><rp>)</rp>");
+            }
+            None => {
+                if in_ruby {
+                    markup.push_str("</ruby>");
+                    in_ruby = false;
+                }
+                markup.push_str(&segment.text);
+            }
+        }
+    }
+    if in_ruby {
+        markup.push_str("</ruby>");
+    }
+    markup
+}
+
+/// How to write furigana out, as picked on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum FuriganaFormat {
+    /// HTML `<ruby>` markup
+    Html,
+    /// Anki's furigana fields, `漢字[かんじ]`
+    Anki,
+    /// Aozora Bunko, `｜漢字《かんじ》`
+    Aozora,
+    /// Readings in brackets after the kanji, `漢字(かんじ)`
+    Parenthetical,
+    /// The `\ruby` command of LaTeX's ruby packages, `\ruby{漢字}{かんじ}`
+    Latex,
+    /// A JSON list of spans with their readings and where they are in the text, in characters
+    Json,
+}
+
+/// A segment as a JSON span, with where it is in the text.
+#[derive(Serialize)]
+struct Span<'a> {
+    text: &'a str,
+    reading: Option<&'a str>,
+    guessed: bool,
+    start: usize,
+    end: usize,
+}
+
+fn escape_latex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '{' | '}' | '$' | '&' | '#' | '_' | '%' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The reading of a segment, with a question mark after it if it was guessed and `mark_guessed`.
+fn marked_reading(segment: &Segment, mark_guessed: bool) -> Option<String> {
+    let reading = segment.reading.as_ref()?;
+    Some(if mark_guessed && segment.guessed {
+        format!("{reading}?")
+    } else {
+        reading.clone()
+    })
+}
+
+/// Writes the segments of a text out with their furigana in `format`, flagging guessed readings
+/// if `mark_guessed`. JSON spans always say whether they were guessed.
+pub fn render(segments: &[Segment], format: FuriganaFormat, mark_guessed: bool) -> String {
+    let mut out = String::new();
+    match format {
+        FuriganaFormat::Html => out = ruby_markup(segments, mark_guessed),
+        FuriganaFormat::Anki => {
+            for segment in segments {
+                match marked_reading(segment, mark_guessed) {
+                    Some(reading) => {
+                        // the base text runs back to the last space, which Anki hides
+                        if !out.is_empty() && !out.ends_with(']') {
+                            out.push(' ');
+                        }
+                        out.push_str(&format!("{}[{reading}]", segment.text));
+                    }
+                    None => out.push_str(&segment.text),
+                }
+            }
+        }
+        FuriganaFormat::Aozora => {
+            for segment in segments {
+                match marked_reading(segment, mark_guessed) {
+                    Some(reading) => out.push_str(&format!("｜{}《{reading}》", segment.text)),
+                    None => out.push_str(&segment.text),
+                }
+            }
+        }
+        FuriganaFormat::Parenthetical => {
+            // a word at a time reads better than a kanji at a time
+            let mut i = 0;
+            while i < segments.len() {
+                let run = segments[i..]
+                    .iter()
+                    .take_while(|segment| segment.reading.is_some())
+                    .count();
+                if run == 0 {
+                    out.push_str(&segments[i].text);
+                    i += 1;
+                    continue;
+                }
+                let run = &segments[i..i + run];
+                let text: String = run.iter().map(|segment| segment.text.as_str()).collect();
+                let reading: String = run
+                    .iter()
+                    .flat_map(|segment| &segment.reading)
+                    .cloned()
+                    .collect();
+                let mark = if mark_guessed && run.iter().any(|segment| segment.guessed) {
+                    "?"
+                } else {
+                    ""
+                };
+                out.push_str(&format!("{text}({reading}{mark})"));
+                i += run.len();
+            }
+        }
+        FuriganaFormat::Latex => {
+            for segment in segments {
+                match marked_reading(segment, mark_guessed) {
+                    Some(reading) => out.push_str(&format!(
+                        "\\ruby{{{}}}{{{reading}}}",
+                        escape_latex(&segment.text)
+                    )),
+                    None => out.push_str(&escape_latex(&segment.text)),
+                }
+            }
+        }
+        FuriganaFormat::Json => {
+            let mut start = 0;
+            let spans: Vec<Span> = segments
+                .iter()
+                .map(|segment| {
+                    let end = start + segment.text.chars().count();
+                    let span = Span {
+                        text: &segment.text,
+                        reading: segment.reading.as_deref(),
+                        guessed: segment.guessed,
+                        start,
+                        end,
+                    };
+                    start = end;
+                    span
+                })
+                .collect();
+            out = serde_json::to_string(&spans).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings_of(kanji: char) -> Vec<String> {
+        let readings: &[&str] = match kanji {
+            '学' => &["ガク", "まな"],
+            '校' => &["コウ", "キョウ"],
+            '日' => &["ニチ", "ジツ", "ひ", "か"],
+            '本' => &["ホン", "もと"],
+            '三' => &["サン", "み", "みっ"],
+            '一' => &["イチ", "イツ", "ひと"],
+            '杯' => &["ハイ", "さかずき"],
+            '美' => &["ビ", "ミ", "うつく"],
+            '人' => &["ジン", "ニン", "ひと"],
+            '受' => &["ジュ", "う", "うけ", "うける"],
+            '付' => &["フ", "つ", "つけ", "つける"],
+            '今' => &["コン", "キン", "いま"],
+            '女' => &["ジョ", "ニョ", "ニョウ", "おんな", "め"],
+            '房' => &["ボウ", "ふさ"],
+            '瓶' => &["ビン", "へい"],
+            _ => &[],
+        };
+        readings.iter().map(|reading| reading.to_string()).collect()
+    }
+
+    fn aligned(text: &str, reading: &str) -> Vec<(String, Option<String>)> {
+        align(text, reading, readings_of)
+            .unwrap()
+            .into_iter()
+            .map(|segment| (segment.text, segment.reading))
+            .collect()
+    }
+
+    fn kanji(text: &str, reading: &str) -> (String, Option<String>) {
+        (text.into(), Some(reading.into()))
+    }
+
+    fn kana(text: &str) -> (String, Option<String>) {
+        (text.into(), None)
+    }
+
+    #[test]
+    fn aligns_through_sound_changes() {
+        assert_eq!(
+            aligned("学校", "がっこう"),
+            [kanji("学", "がっ"), kanji("校", "こう")]
+        );
+        assert_eq!(
+            aligned("日本", "にほん"),
+            [kanji("日", "に"), kanji("本", "ほん")]
+        );
+        assert_eq!(
+            aligned("三杯", "さんばい"),
+            [kanji("三", "さん"), kanji("杯", "ばい")]
+        );
+        assert_eq!(
+            aligned("一杯", "いっぱい"),
+            [kanji("一", "いっ"), kanji("杯", "ぱい")]
+        );
+        assert_eq!(
+            aligned("人々", "ひとびと"),
+            [kanji("人", "ひと"), kanji("々", "びと")]
+        );
+        assert_eq!(
+            aligned("女房", "にょぼう"),
+            [kanji("女", "にょ"), kanji("房", "ぼう")]
+        );
+    }
+
+    #[test]
+    fn aligns_around_kana() {
+        assert_eq!(
+            aligned("美しい", "うつくしい"),
+            [kanji("美", "うつく"), kana("しい")]
+        );
+        assert_eq!(
+            aligned("受付", "うけつけ"),
+            [kanji("受", "うけ"), kanji("付", "つけ")]
+        );
+        assert_eq!(
+            aligned("ビール瓶", "びいるびん"),
+            [kana("ビール"), kanji("瓶", "びん")]
+        );
+        // read as a whole, neither kanji has a reading that fits
+        assert_eq!(
+            aligned("今日は", "きょうは"),
+            [kanji("今日", "きょう"), kana("は")]
+        );
+        // nothing better to do than the word as a whole
+        assert_eq!(aligned("学校", "がくせい"), [kanji("学校", "がくせい")]);
+        // kanji the dictionary has no readings for are still kanji
+        assert_eq!(aligned("鬱だ", "うつだ"), [kanji("鬱", "うつ"), kana("だ")]);
+        assert_eq!(
+            aligned("学鬱", "がくうつ"),
+            [kanji("学", "がく"), kanji("鬱", "うつ")]
+        );
+        assert_eq!(align("美しい", "うつくしく", readings_of), None);
+    }
+
+    #[test]
+    fn inflected_readings() {
+        let cases = [
+            ("取る", "とる", "取って", Some("とって")),
+            ("美しい", "うつくしい", "美しかった", Some("うつくしかった")),
+            ("食べる", "たべる", "食べさせられた", Some("たべさせられた")),
+            (
+                "取り扱う",
+                "とりあつかう",
+                "取り扱わない",
+                Some("とりあつかわない"),
+            ),
+            ("学校", "がっこう", "学校", Some("がっこう")),
+            ("来る", "くる", "来た", Some("きた")),
+            ("来る", "くる", "来なかった", Some("こなかった")),
+            ("来る", "くる", "来れば", Some("くれば")),
+            ("行く", "いく", "来た", None),
+        ];
+        for (lemma, reading, surface, expected) in cases {
+            assert_eq!(
+                inflected_reading(lemma, reading, surface).as_deref(),
+                expected,
+                "{surface}"
+            );
+        }
+    }
+
+    #[test]
+    fn renders_every_format() {
+        let segments = align("美しい学校", "うつくしいがっこう", readings_of).unwrap();
+        let rendered = |format| render(&segments, format, true);
+        assert_eq!(
+            rendered(FuriganaFormat::Anki),
+            "美[うつく]しい 学[がっ]校[こう]"
+        );
+        assert_eq!(
+            rendered(FuriganaFormat::Aozora),
+            "｜美《うつく》しい｜学《がっ》｜校《こう》"
+        );
+        assert_eq!(
+            rendered(FuriganaFormat::Parenthetical),
+            "美(うつく)しい学校(がっこう)"
+        );
+        assert_eq!(
+            rendered(FuriganaFormat::Latex),
+            "\\ruby{美}{うつく}しい\\ruby{学}{がっ}\\ruby{校}{こう}"
+        );
+        assert_eq!(
+            rendered(FuriganaFormat::Json),
+            r#"[{"text":"美","reading":"うつく","guessed":false,"start":0,"end":1},
+               {"text":"しい","reading":null,"guessed":false,"start":1,"end":3},
+               {"text":"学","reading":"がっ","guessed":false,"start":3,"end":4},
+               {"text":"校","reading":"こう","guessed":false,"start":4,"end":5}]"#
+                .replace(char::is_whitespace, "")
+        );
+    }
+
+    #[test]
+    fn guesses_readings_from_kanji() {
+        fn kanji_readings(kanji: char) -> KanjiReadings {
+            let (on, kun): (&[&str], &[(&str, Option<&str>)]) = match kanji {
+                '美' => (&["ビ", "ミ"], &[("うつく", Some("しい"))]),
+                '人' => (&["ジン", "ニン"], &[("ひと", None)]),
+                '山' => (&["サン", "セン"], &[("やま", None)]),
+                '田' => (&["デン"], &[("た", None)]),
+                '学' => (&["ガク"], &[("まな", Some("ぶ"))]),
+                _ => (&[], &[]),
+            };
+            KanjiReadings {
+                on: on.iter().map(|on| on.to_string()).collect(),
+                kun: kun
+                    .iter()
+                    .map(|(stem, okurigana)| (stem.to_string(), okurigana.map(String::from)))
+                    .collect(),
+            }
+        }
+        let guessed = |text| {
+            guess(text, kanji_readings)
+                .unwrap()
+                .into_iter()
+                .map(|segment| {
+                    assert_eq!(segment.guessed, segment.reading.is_some());
+                    (segment.text, segment.reading)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(guessed("美人"), [kanji("美", "び"), kanji("人", "じん")]);
+        assert_eq!(guessed("美しい"), [kanji("美", "うつく"), kana("しい")]);
+        assert_eq!(
+            guessed("山田さん"),
+            [kanji("山", "さん"), kanji("田", "でん"), kana("さん")]
+        );
+        assert_eq!(guessed("人々"), [kanji("人", "ひと"), kanji("々", "びと")]);
+        assert_eq!(guessed("学ぶ"), [kanji("学", "まな"), kana("ぶ")]);
+        assert_eq!(guess("鬱", kanji_readings), None);
+
+        let segments = guess("美人", kanji_readings).unwrap();
+        assert_eq!(
+            render(&segments, FuriganaFormat::Anki, true),
+            "美[び?]人[じん?]"
+        );
+        assert_eq!(
+            render(&segments, FuriganaFormat::Parenthetical, true),
+            "美人(びじん?)"
+        );
+        assert_eq!(
+            render(&segments, FuriganaFormat::Aozora, false),
+            "｜美《び》｜人《じん》"
+        );
+    }
+
+    #[test]
+    fn policies_leave_out_furigana() {
+        fn level_of(kanji: char) -> KanjiLevel {
+            let (grade, jlpt) = match kanji {
+                '学' => (1, 4),
+                '校' => (1, 4),
+                '美' => (3, 3),
+                '瓶' => (8, 1),
+                _ => return KanjiLevel::default(),
+            };
+            KanjiLevel {
+                grade: Some(grade),
+                jlpt: Some(jlpt),
+            }
+        }
+        let annotated = |policy: &mut FuriganaPolicy, text, lemma, reading| {
+            let mut segments = align(text, reading, readings_of).unwrap();
+            policy.apply(text, lemma, &mut segments, level_of);
+            render(&segments, FuriganaFormat::Parenthetical, false)
+        };
+
+        let mut policy = FuriganaPolicy {
+            above_grade: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(annotated(&mut policy, "学校", "学校", "がっこう"), "学校");
+        assert_eq!(
+            annotated(&mut policy, "美しい", "美しい", "うつくしい"),
+            "美(うつく)しい"
+        );
+        assert_eq!(
+            annotated(&mut policy, "ビール瓶", "ビール瓶", "びいるびん"),
+            "ビール瓶(びん)"
+        );
+
+        let mut policy = FuriganaPolicy {
+            above_jlpt: Some(3),
+            known: Some(["ビール瓶".to_string()].into_iter().collect()),
+            ..Default::default()
+        };
+        assert_eq!(
+            annotated(&mut policy, "美しい", "美しい", "うつくしい"),
+            "美しい"
+        );
+        assert_eq!(
+            annotated(&mut policy, "ビール瓶", "ビール瓶", "びいるびん"),
+            "ビール瓶"
+        );
+
+        let mut policy = FuriganaPolicy {
+            first_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            annotated(&mut policy, "美しかった", "美しい", "うつくしかった"),
+            "美(うつく)しかった"
+        );
+        assert_eq!(
+            annotated(&mut policy, "美しい", "美しい", "うつくしい"),
+            "美しい"
+        );
+        assert_eq!(
+            annotated(&mut policy, "学校", "学校", "がっこう"),
+            "学校(がっこう)"
+        );
+    }
+
+    #[test]
+    fn ruby_markup_keeps_kana_out() {
+        let segments = align("美しい学校", "うつくしいがっこう", readings_of).unwrap();
+        assert_eq!(
+            ruby_markup(&segments, true),
+            "<ruby>美<rp>(</rp><rt>うつく</rt><rp>)</rp></ruby>しい\
+             <ruby>学<rp>(</rp><rt>がっ</rt><rp>)</rp>校<rp>(</rp><rt>こう</rt><rp>)</rp></ruby>"
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Persistent inverted index over analysed documents, so looking up where a word is used doesn't
+//! need the NLP engine at all.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    document::{Document, DocumentChunk},
+    nlp::{Morphology, UposTag, WordRole},
+};
+
+/// Bump whenever the serialized index changes shape.
+const INDEX_FORMAT_VERSION: u32 = 2;
+
+/// Name the index is saved under in the cache directory.
+pub const INDEX_FILE: &str = "index.bin";
+
+/// Which form of a word a term was taken from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+pub enum TermKind {
+    /// Dictionary form, e.g. 食べる
+    Lemma,
+    /// As written in the subtitles, e.g. 食べた
+    Surface,
+    /// Kana reading of the dictionary entry, e.g. たべる
+    Reading,
+}
+
+/// Where a term occurs: the word at position `word` of chunk `chunk` of document `doc`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Posting {
+    pub doc: u32,
+    pub chunk: u32,
+    pub word: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexedWord {
+    pub surface: String,
+    pub lemma: String,
+}
+
+/// A single token as split by the NLP engine, before inflections are merged into words.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexedUnit {
+    pub surface: String,
+    pub lemma: String,
+    pub pos: UposTag,
+    /// Role of the word this unit is part of.
+    pub role: WordRole,
+    /// Dependency relation to its head, e.g. `obj`.
+    pub relation: String,
+    /// Index of the word this unit is part of.
+    pub word: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexedChunk {
+    pub start: Option<Duration>,
+    pub end: Option<Duration>,
+    pub text: String,
+    pub words: Vec<IndexedWord>,
+    pub units: Vec<IndexedUnit>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexedDocument {
+    pub source: Option<PathBuf>,
+    pub content_hash: u64,
+    pub chunks: Vec<IndexedChunk>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct CorpusIndex {
+    format_version: u32,
+    /// Version of the NLP engine that analysed the indexed documents.
+    engine: String,
+    docs: Vec<IndexedDocument>,
+    /// Content hashes of every document we've been given, including ones that were not indexed
+    /// because they turned out to be duplicates.
+    seen: HashSet<u64>,
+    postings: HashMap<(TermKind, String), Vec<Posting>>,
+}
+
+impl CorpusIndex {
+    pub fn new(engine: &str) -> Self {
+        Self {
+            format_version: INDEX_FORMAT_VERSION,
+            engine: engine.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Loads a saved index, or returns `None` if there isn't a usable one at `path`.
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = fs::read(path).ok()?;
+        let index: Self = bincode::deserialize(&bytes).ok()?;
+        (index.format_version == INDEX_FORMAT_VERSION).then_some(index)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bincode::serialize(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn engine(&self) -> &str {
+        &self.engine
+    }
+
+    /// Throws everything away if the index was built by a different engine version.
+    pub fn ensure_engine(&mut self, engine: &str) {
+        if self.engine != engine {
+            *self = Self::new(engine);
+        }
+    }
+
+    /// Whether a document with this content hash has already been given to the index.
+    pub fn contains(&self, content_hash: u64) -> bool {
+        self.seen.contains(&content_hash)
+    }
+
+    /// Remembers a document without indexing it, so it isn't considered new next time.
+    pub fn mark_seen(&mut self, content_hash: u64) {
+        self.seen.insert(content_hash);
+    }
+
+    /// Forgets the documents that are no longer in the corpus, `present` having the content hashes
+    /// of the ones that are. Returns whether there were any.
+    pub fn prune(&mut self, present: &HashSet<u64>) -> bool {
+        let seen = self.seen.len();
+        self.seen.retain(|hash| present.contains(hash));
+        self.retain(|doc| present.contains(&doc.content_hash));
+        self.seen.len() != seen
+    }
+
+    /// Drops documents from the index, e.g. ones a better release has since taken the place of.
+    /// They still count as seen, so they don't get indexed again.
+    pub fn remove(&mut self, content_hashes: &HashSet<u64>) {
+        self.retain(|doc| !content_hashes.contains(&doc.content_hash));
+    }
+
+    /// Keeps only the documents `keep` returns true for, renumbering the postings to match.
+    fn retain(&mut self, keep: impl Fn(&IndexedDocument) -> bool) {
+        let mut renumbered = Vec::with_capacity(self.docs.len());
+        let mut kept = 0;
+        for doc in &self.docs {
+            if keep(doc) {
+                renumbered.push(Some(kept));
+                kept += 1;
+            } else {
+                renumbered.push(None);
+            }
+        }
+        if kept == self.docs.len() as u32 {
+            return;
+        }
+
+        let mut renumbered_docs = renumbered.iter();
+        self.docs
+            .retain(|_| renumbered_docs.next().unwrap().is_some());
+        self.postings.retain(|_, postings| {
+            postings.retain_mut(|posting| match renumbered[posting.doc as usize] {
+                Some(doc) => {
+                    posting.doc = doc;
+                    true
+                }
+                None => false,
+            });
+            !postings.is_empty()
+        });
+    }
+
+    /// Indexes an analysed document. Does nothing if it was given to the index already.
+    pub fn add(&mut self, doc: &Document) {
+        let content_hash = doc.content_hash();
+        if self.seen.contains(&content_hash) {
+            return;
+        }
+        let analysis = doc.analysis().expect("document must be analyzed");
+        let doc_i = self.docs.len() as u32;
+        let mut chunks = Vec::with_capacity(analysis.len());
+
+        for (chunk_i, (chunk, analysis)) in doc.chunks().iter().zip(analysis).enumerate() {
+            let morphology = Morphology::from_analysis(analysis.clone());
+            let mut words = vec![];
+            let mut units = vec![];
+            for (word_i, word) in morphology.words().enumerate() {
+                let posting = Posting {
+                    doc: doc_i,
+                    chunk: chunk_i as u32,
+                    word: word_i as u32,
+                };
+                let lemma = word.lemma();
+                let reading = word
+                    .lookup(true)
+                    .and_then(|(entry, _)| entry.reading_elements().next())
+                    .map(|reading| reading.text.to_string());
+
+                self.add_posting(TermKind::Lemma, lemma.clone(), posting);
+                self.add_posting(TermKind::Surface, word.text.clone(), posting);
+                if let Some(reading) = reading {
+                    self.add_posting(TermKind::Reading, reading, posting);
+                }
+                words.push(IndexedWord {
+                    surface: word.text.clone(),
+                    lemma,
+                });
+                // words keep their units in the order the engine gave them to us
+                for unit in &word.upos_subunits {
+                    units.push(IndexedUnit {
+                        surface: unit.unit.clone(),
+                        lemma: unit.lemma.clone(),
+                        pos: unit.class,
+                        role: word.role,
+                        relation: analysis
+                            .relations
+                            .get(units.len())
+                            .cloned()
+                            .unwrap_or_default(),
+                        word: word_i as u32,
+                    });
+                }
+            }
+
+            let (start, end) = match chunk {
+                DocumentChunk::Subs(sub) => (Some(sub.start), Some(sub.end)),
+                DocumentChunk::Plaintext(_) => (None, None),
+            };
+            chunks.push(IndexedChunk {
+                start,
+                end,
+                text: chunk.contents().to_string(),
+                words,
+                units,
+            });
+        }
+
+        self.docs.push(IndexedDocument {
+            source: doc.source().map(Path::to_path_buf),
+            content_hash,
+            chunks,
+        });
+        self.seen.insert(content_hash);
+    }
+
+    fn add_posting(&mut self, kind: TermKind, term: String, posting: Posting) {
+        let postings = self.postings.entry((kind, term)).or_default();
+        // the same word can't be added twice, but a merged word can repeat a posting
+        if postings.last() != Some(&posting) {
+            postings.push(posting);
+        }
+    }
+
+    pub fn lookup(&self, kind: TermKind, term: &str) -> &[Posting] {
+        self.postings
+            .get(&(kind, term.to_string()))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Every term of the given kind, along with where it occurs, in no particular order.
+    pub fn terms(&self, kind: TermKind) -> impl Iterator<Item = (&str, &[Posting])> {
+        self.postings
+            .iter()
+            .filter(move |((term_kind, _), _)| *term_kind == kind)
+            .map(|((_, term), postings)| (term.as_str(), postings.as_slice()))
+    }
+
+    pub fn document(&self, doc: u32) -> &IndexedDocument {
+        &self.docs[doc as usize]
+    }
+
+    pub fn documents(&self) -> &[IndexedDocument] {
+        &self.docs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nlp::{Analysis, UposTag, WordUnit};
+
+    fn analysed_doc(words: &[(&str, &str, UposTag)]) -> Document {
+        let mut doc = Document::new(vec![DocumentChunk::Plaintext(
+            words.iter().map(|w| w.0).collect(),
+        )]);
+        let units = words
+            .iter()
+            .map(|&(unit, lemma, class)| WordUnit {
+                unit: unit.into(),
+                lemma: lemma.into(),
+                class,
+            })
+            .collect();
+        let deps = (0..words.len()).collect();
+        let relations = vec![String::new(); words.len()];
+        doc.restore(
+            None,
+            Some(vec![Analysis {
+                units,
+                deps,
+                relations,
+            }]),
+        );
+        doc
+    }
+
+    #[test]
+    fn finds_words_by_lemma_surface_and_reading() {
+        let mut index = CorpusIndex::new("test");
+        let doc = analysed_doc(&[
+            ("ボール", "ボール", UposTag::Noun),
+            ("を", "を", UposTag::Adposition),
+            ("取っ", "取る", UposTag::Verb),
+            ("て", "て", UposTag::SubordinatingConjunction),
+        ]);
+        index.add(&doc);
+        assert!(index.contains(doc.content_hash()));
+
+        let expected = [Posting {
+            doc: 0,
+            chunk: 0,
+            word: 2,
+        }];
+        assert_eq!(index.lookup(TermKind::Lemma, "取る"), expected);
+        assert_eq!(index.lookup(TermKind::Surface, "取って"), expected);
+        assert_eq!(index.lookup(TermKind::Reading, "とる"), expected);
+        assert!(index.lookup(TermKind::Lemma, "食べる").is_empty());
+
+        let path =
+            std::env::temp_dir().join(format!("omoide-index-test-{}.bin", std::process::id()));
+        index.save(&path).unwrap();
+        let loaded = CorpusIndex::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.lookup(TermKind::Lemma, "取る"), expected);
+        assert_eq!(loaded.document(0).chunks[0].words[2].surface, "取って");
+    }
+
+    #[test]
+    fn forgets_documents_gone_from_the_corpus() {
+        let mut index = CorpusIndex::new("test");
+        let docs = [
+            analysed_doc(&[("取っ", "取る", UposTag::Verb)]),
+            analysed_doc(&[("ボール", "ボール", UposTag::Noun)]),
+            analysed_doc(&[("取る", "取る", UposTag::Verb)]),
+        ];
+        for doc in &docs {
+            index.add(doc);
+        }
+        index.mark_seen(42);
+
+        let present = HashSet::from([docs[0].content_hash(), docs[2].content_hash()]);
+        assert!(index.prune(&present));
+        assert!(!index.prune(&present));
+        assert!(!index.contains(docs[1].content_hash()) && !index.contains(42));
+        assert_eq!(index.documents().len(), 2);
+        assert!(index.lookup(TermKind::Lemma, "ボール").is_empty());
+        let docs_with = |lemma| -> Vec<u32> {
+            index
+                .lookup(TermKind::Lemma, lemma)
+                .iter()
+                .map(|posting| posting.doc)
+                .collect()
+        };
+        assert_eq!(docs_with("取る"), [0, 1]);
+        assert_eq!(index.document(1).content_hash, docs[2].content_hash());
+    }
+}
@@ -1,120 +1,406 @@
+use anyhow::Context;
 use kanjidic_parser::kanjidic::Kanjidic;
-use kanjidic_types::Character;
+use kanjidic_types::{radical::RadicalKind, Character, Grade};
 use lazy_static::lazy_static;
 use pyo3::prelude::*;
 use regex::Regex;
 use wana_kana::to_hiragana::to_hiragana;
 
-use crate::nlp::Word;
+use crate::{
+    data, furigana,
+    nlp::Word,
+    prebuilt::{self, Records, Table, TableBuilder},
+};
+
+/// Parses a KANJIDIC2 XML release, returning its kanji along with its version and when it was
+/// created.
+pub fn parse_kanjidic(xml: &str) -> anyhow::Result<(Vec<Character>, String, String)> {
+    // roxmltree won't have the DTD, so skip it
+    let start = xml.find("<kanjidic2>").context("No <kanjidic2> element")?;
+    let kanjidic = Kanjidic::try_from(&xml[start..])?;
+    let header = kanjidic.header;
+    let version = format!(
+        "{}-{}",
+        header.database_version.year, header.database_version.version
+    );
+    let created = format!(
+        "{}-{:02}-{:02}",
+        header.date_of_creation.year, header.date_of_creation.month, header.date_of_creation.day
+    );
+    Ok((kanjidic.characters, version, created))
+}
+
+fn load_kanjidic() -> Vec<Character> {
+    let path = data::data_dir().join(data::KANJIDIC_FILE);
+    if !path.is_file() {
+        eprintln!(
+            "No KANJIDIC in '{}', import it with `omoide manage --import-kanjidic <PATH>`",
+            data::data_dir().display()
+        );
+        return vec![];
+    }
+    match data::read_xml(&path).and_then(|xml| parse_kanjidic(&xml)) {
+        Ok((characters, _, _)) => characters,
+        Err(e) => {
+            eprintln!("Couldn't read '{}': {e:#}", path.display());
+            vec![]
+        }
+    }
+}
+
+/// Prebuilds KANJIDIC into the data directory, so it doesn't have to be parsed and indexed
+/// every time. Returns the number of kanji.
+pub fn build_index() -> anyhow::Result<usize> {
+    let source = data::data_dir().join(data::KANJIDIC_FILE);
+    let (characters, _, _) = parse_kanjidic(&data::read_xml(&source)?)?;
+    let table = build_table(&characters, true, prebuilt::stamp(Some(&source)))?;
+    data::write_atomically(&data::data_dir().join(data::KANJIDIC_INDEX_FILE), &table)?;
+    Ok(characters.len())
+}
+
+fn open_kanjidic() -> KanjiIndex {
+    let path = data::data_dir().join(data::KANJIDIC_INDEX_FILE);
+    if path.is_file() {
+        let source = data::data_dir().join(data::KANJIDIC_FILE);
+        match Table::open(&path, prebuilt::stamp(Some(&source))) {
+            Ok(table) => return KanjiIndex::from_table(table),
+            Err(e) => eprintln!("{e:#}, rebuild it with `omoide manage --build-index`"),
+        }
+    }
+    KanjiIndex::new(load_kanjidic())
+}
 
 lazy_static! {
-    static ref KANJIDIC: Kanjidic = {
-        let xml = include_str!("../blobs/kanjidic2.xml");
-        let start = xml.find("<kanjidic2>").unwrap();
-        let skipped = std::str::from_utf8(&xml.as_bytes()[start..]).unwrap();
-        Kanjidic::try_from(skipped).expect("couldn't parse kanjidic file")
-    };
+    static ref KANJI_INDEX: KanjiIndex = open_kanjidic();
     pub(crate) static ref KANJI_RE: Regex = Regex::new(r"\p{Han}").unwrap();
 }
 
-pub fn lookup_kanji(by: char) -> Option<Character> {
-    for entry in KANJIDIC.characters.iter() {
-        if entry.literal == by {
-            return Some(entry.clone());
+/// Kanji to look for in a `KanjiIndex`, matching all of the criteria given.
+#[derive(Clone, Debug, Default)]
+pub struct KanjiQuery {
+    /// Number of the classical (Kangxi) radical, from 1 to 214.
+    pub radical: Option<u8>,
+    pub strokes: Option<u8>,
+    pub grade: Option<Grade>,
+    /// Level in the old four level JLPT, which is what KANJIDIC has.
+    pub jlpt: Option<u8>,
+    /// Only kanji ranked this common or more in newspapers, where 1 is the most common.
+    pub max_frequency: Option<u16>,
+}
+
+/// How far into learning kanji a kanji comes, going by KANJIDIC.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KanjiLevel {
+    /// School grade, numbered as in KANJIDIC: 1 to 6 for primary school, 8 for the rest of the
+    /// jōyō kanji, and 9 and 10 for kanji used in names.
+    pub grade: Option<u8>,
+    /// Level in the old four level JLPT, from 4 (easiest) to 1.
+    pub jlpt: Option<u8>,
+}
+
+/// KANJIDIC, indexed by literal, and by everything `KanjiQuery` can look for.
+pub struct KanjiIndex {
+    characters: Records<Character>,
+}
+
+fn radical_key(radical: u8) -> String {
+    format!("radical:{radical}")
+}
+
+fn strokes_key(strokes: u8) -> String {
+    format!("strokes:{strokes}")
+}
+
+fn grade_key(grade: Grade) -> String {
+    format!("grade:{grade:?}")
+}
+
+fn jlpt_key(jlpt: u8) -> String {
+    format!("jlpt:{jlpt}")
+}
+
+/// Leads to the kanji with a frequency rank, most common first.
+const BY_FREQUENCY: &str = "frequency";
+
+/// Indexes kanji by literal and by everything `KanjiQuery` can look for, leaving the kanji
+/// themselves out if we already have them in memory.
+fn build_table(
+    characters: &[Character],
+    with_characters: bool,
+    stamp: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut builder = TableBuilder::new();
+    for kanji in characters {
+        // bincode can't do `Character`, it leaves out empty fields
+        let i = match with_characters {
+            true => builder.push(&serde_json::to_vec(kanji)?),
+            false => builder.push(&[]),
+        };
+        builder.key(kanji.literal.encode_utf8(&mut [0; 4]), i);
+        if let Some(radical) = classical_radical(kanji) {
+            builder.key(&radical_key(radical), i);
+        }
+        builder.key(&strokes_key(kanji.stroke_counts.accepted), i);
+        if let Some(grade) = kanji.grade {
+            builder.key(&grade_key(grade), i);
+        }
+        if let Some(jlpt) = kanji.jlpt {
+            builder.key(&jlpt_key(jlpt), i);
+        }
+    }
+    let mut ranked: Vec<(u16, u32)> = characters
+        .iter()
+        .enumerate()
+        .filter_map(|(i, kanji)| kanji.frequency.map(|rank| (rank, i as u32)))
+        .collect();
+    ranked.sort_unstable();
+    for (_, i) in ranked {
+        builder.key(BY_FREQUENCY, i);
+    }
+    builder.finish(stamp)
+}
+
+impl KanjiIndex {
+    pub fn new(characters: Vec<Character>) -> Self {
+        let table = build_table(&characters, false, 0)
+            .and_then(Table::from_bytes)
+            .expect("couldn't index KANJIDIC");
+        Self {
+            characters: Records::with_decoded(table, characters),
+        }
+    }
+
+    fn from_table(table: Table) -> Self {
+        Self {
+            characters: Records::new(table, |kanji| Ok(serde_json::from_slice(kanji)?)),
         }
     }
-    None
+
+    pub fn get(&self, literal: char) -> Option<&Character> {
+        self.characters
+            .lookup(literal.encode_utf8(&mut [0; 4]))
+            .next()
+    }
+
+    pub fn len(&self) -> usize {
+        self.characters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.characters.is_empty()
+    }
+
+    /// Kanji matching everything asked for, most common first, with the ones without a
+    /// frequency rank last in KANJIDIC order.
+    pub fn query(&self, query: &KanjiQuery) -> Vec<&Character> {
+        let table = self.characters.table();
+        // start from the fewest candidates any one criterion allows, and check the rest
+        let narrowed = [
+            query.radical.map(radical_key),
+            query.strokes.map(strokes_key),
+            query.grade.map(grade_key),
+            query.jlpt.map(jlpt_key),
+        ];
+        let candidates = narrowed
+            .iter()
+            .flatten()
+            .map(|key| table.get(key).collect::<Vec<_>>())
+            .min_by_key(Vec::len);
+        let candidates = match (candidates, query.max_frequency) {
+            (Some(candidates), _) => candidates,
+            (None, Some(max)) => table
+                .get(BY_FREQUENCY)
+                .take_while(|&i| {
+                    self.characters
+                        .get(i)
+                        .frequency
+                        .is_some_and(|rank| rank <= max)
+                })
+                .collect(),
+            (None, None) => (0..self.characters.len() as u32).collect(),
+        };
+
+        let mut found: Vec<&Character> = candidates
+            .into_iter()
+            .map(|i| self.characters.get(i))
+            .filter(|kanji| {
+                query
+                    .radical
+                    .is_none_or(|radical| classical_radical(kanji) == Some(radical))
+                    && query
+                        .strokes
+                        .is_none_or(|strokes| kanji.stroke_counts.accepted == strokes)
+                    && query.grade.is_none_or(|grade| kanji.grade == Some(grade))
+                    && query.jlpt.is_none_or(|jlpt| kanji.jlpt == Some(jlpt))
+                    && query
+                        .max_frequency
+                        .is_none_or(|max| kanji.frequency.is_some_and(|rank| rank <= max))
+            })
+            .collect();
+        // stable, so the unranked ones stay in order
+        found.sort_by_key(|kanji| kanji.frequency.unwrap_or(u16::MAX));
+        found
+    }
+}
+
+fn classical_radical(kanji: &Character) -> Option<u8> {
+    kanji
+        .radicals
+        .iter()
+        .find(|radical| radical.kind == RadicalKind::Classical)
+        .map(|radical| radical.radical.into())
+}
+
+/// Repeats the kanji before it. Counts as a kanji to `is_kanji`, though it's not one to learn.
+pub const ITERATION_MARK: char = '々';
+
+pub fn is_kanji(c: char) -> bool {
+    KANJI_RE.is_match(c.encode_utf8(&mut [0; 4]))
+}
+
+/// The index over KANJIDIC, as loaded from the data directory.
+pub fn kanji_index() -> &'static KanjiIndex {
+    &KANJI_INDEX
+}
+
+pub fn lookup_kanji(by: char) -> Option<Character> {
+    KANJI_INDEX.get(by).cloned()
 }
 
 pub fn lookup_kanji_readings(
     by: char,
     longest_first: bool,
 ) -> Option<impl Iterator<Item = String>> {
-    if let Some(kanji) = lookup_kanji(by) {
+    if let Some(kanji) = KANJI_INDEX.get(by) {
         use kanjidic_types::Reading::*;
-        let mut readings: Vec<String> = kanji
+        let mut readings: Vec<&str> = kanji
             .readings
-            .into_iter()
+            .iter()
             .filter_map(|r| match r {
-                Onyomi(s) => Some(s),
-                Kunyomi(s) => Some(s.reading),
+                Onyomi(s) => Some(s.as_str()),
+                Kunyomi(s) => Some(s.reading.as_str()),
                 _ => None,
             })
             .collect();
         if longest_first {
             readings.sort_by(|a, b| b.len().cmp(&a.len()));
         }
-        Some(readings.into_iter().map(|r| to_hiragana(r.as_str())))
+        Some(readings.into_iter().map(to_hiragana))
     } else {
         None
     }
 }
 
+/// Readings of a kanji as they might be written in furigana, in hiragana. Kun readings are
+/// also given with their okurigana, some or all of which is often left out of the word, as in
+/// 受付 (うけつけ).
+pub fn furigana_readings(by: char) -> Vec<String> {
+    use kanjidic_types::Reading::*;
+    let Some(kanji) = KANJI_INDEX.get(by) else {
+        return vec![];
+    };
+    let mut readings = vec![];
+    for reading in &kanji.readings {
+        match reading {
+            Onyomi(onyomi) => readings.push(to_hiragana(onyomi)),
+            Kunyomi(kunyomi) => {
+                let stem = to_hiragana(&kunyomi.reading);
+                for okurigana in kunyomi.okurigana.iter() {
+                    let okurigana = to_hiragana(okurigana);
+                    for (end, c) in okurigana.char_indices() {
+                        readings.push(format!("{stem}{}", &okurigana[..end + c.len_utf8()]));
+                    }
+                }
+                readings.push(stem);
+            }
+            _ => {}
+        }
+    }
+    readings
+}
+
+/// A kanji's on and kun readings, to guess the reading of a word that isn't in JMdict from.
+pub fn kanji_readings(by: char) -> furigana::KanjiReadings {
+    use kanjidic_types::{kunyomi::KunyomiKind, Reading::*};
+    let mut readings = furigana::KanjiReadings::default();
+    let Some(kanji) = KANJI_INDEX.get(by) else {
+        return readings;
+    };
+    for reading in &kanji.readings {
+        match reading {
+            Onyomi(onyomi) => readings.on.push(onyomi.clone()),
+            // prefixes and suffixes are only read that way next to other words
+            Kunyomi(kunyomi) if kunyomi.kind == KunyomiKind::Normal => readings
+                .kun
+                .push((kunyomi.reading.clone(), kunyomi.okurigana.clone())),
+            _ => {}
+        }
+    }
+    readings
+}
+
+/// How far into learning kanji `by` comes, going by its grade and JLPT level in KANJIDIC.
+pub fn kanji_level(by: char) -> KanjiLevel {
+    let Some(kanji) = KANJI_INDEX.get(by) else {
+        return KanjiLevel::default();
+    };
+    KanjiLevel {
+        grade: kanji.grade.map(|grade| match grade {
+            Grade::Kyouiku(grade) => grade,
+            Grade::Jouyou => 8,
+            Grade::Jinmeiyou => 9,
+            Grade::JinmeiyouJouyouVariant => 10,
+        }),
+        jlpt: kanji.jlpt,
+    }
+}
+
 #[pymethods]
 impl Word {
+    /// How the word is read as it's written here, inflected, going by the reading JMdict has
+    /// for its dictionary form. `None` if it isn't in JMdict, see `furigana_segments` for a
+    /// guess at it.
+    pub fn reading(&self) -> Option<String> {
+        let (entry, lemma) = self.lookup(true)?;
+        let reading = &entry.reading_elements().next()?.text;
+        // if the word is more than the dictionary form, like a compound with only some of it in
+        // JMdict, the dictionary form's reading is the best we have
+        Some(
+            furigana::inflected_reading(&lemma, reading, &self.text)
+                .unwrap_or_else(|| reading.clone()),
+        )
+    }
+
     pub fn ruby_furigana(&self) -> Option<String> {
-        let mut reading = None;
-        if self.has_kanji() {
-            if let Some((e, _)) = self.lookup(true) {
-                reading = e.reading_elements().next();
-            }
+        if !self.has_kanji() {
+            return None;
         }
-        // TODO: best effort for words not found in dictionary?
-        if reading.is_none() {
+        let segments = self.furigana_segments();
+        if segments.iter().all(|segment| segment.reading.is_none()) {
             return None;
         }
-        let reading = reading.unwrap().text;
-        let mut markup = String::new();
-        let mut stack = &self.text[..];
-        let mut stack_r = &reading[..];
-        let mut kanjiwise_markup = String::new();
-        while let Some(re_match) = KANJI_RE.find(stack) {
-            let skipped_chars = &stack[..re_match.start()].chars().count();
-            if *skipped_chars > 0 {
-                kanjiwise_markup.push_str("</ruby>");
-                kanjiwise_markup.push_str(&stack[..re_match.start()]);
-                kanjiwise_markup.push_str("<ruby>");
-            }
-            stack = &stack[re_match.start()..];
-            let stack_r_skip = stack_r
-                .char_indices()
-                .map(|(idx, _)| idx)
-                .skip(*skipped_chars)
-                .next()
-                .unwrap_or(stack_r.len());
-            stack_r = &stack_r[stack_r_skip..];
-            let kanji = stack.chars().next().unwrap();
-            kanjiwise_markup.push(kanji);
-
-            if let Some(mut kanji_readings) = lookup_kanji_readings(kanji, true) {
-                if let Some(matched) = kanji_readings.find(|r| stack_r.starts_with(r)) {
-                    stack = &stack[kanji.len_utf8()..];
-                    stack_r = &stack_r[matched.len()..];
-                    kanjiwise_markup.push_str("<rp>(</rp><rt>");
-                    kanjiwise_markup.push_str(&matched);
-                    kanjiwise_markup.push_str("</rt><rp>)</rp>");
-                    continue;
-                }
-            }
+        Some(furigana::ruby_markup(&segments, true))
+    }
+}
 
-            kanjiwise_markup.clear();
-            break;
-        }
+impl Word {
+    fn align_furigana(&self, reading: &str) -> Vec<furigana::Segment> {
+        // fall back to a simpler style if we can't align the furigana to individual kanji
+        furigana::align(&self.text, reading, furigana_readings)
+            .unwrap_or_else(|| vec![furigana::Segment::whole(&self.text, reading)])
+    }
 
-        markup.push_str("<ruby>");
-        if !kanjiwise_markup.is_empty() {
-            markup.push_str(&kanjiwise_markup);
-            markup.push_str(stack); // remaining kana
-        } else {
-            markup.push_str(&self.text);
-            // we failed to align the furigana to individual kanji, so use a simpler style
-            markup.push_str("<rp>(</rp><rt>");
-            markup.push_str(&reading);
-            markup.push_str("</rt><rp>)</rp>");
+    /// The word split into kanji with their readings and kana, or as it is if it's all kana.
+    /// Words that aren't in JMdict get readings guessed from their kanji, if KANJIDIC has any.
+    pub fn furigana_segments(&self) -> Vec<furigana::Segment> {
+        if !self.has_kanji() {
+            return vec![furigana::Segment::bare(&self.text)];
+        }
+        match self.reading() {
+            Some(reading) => self.align_furigana(&reading),
+            None => furigana::guess(&self.text, kanji_readings)
+                .unwrap_or_else(|| vec![furigana::Segment::bare(&self.text)]),
         }
-
-        markup.push_str("</ruby>");
-        Some(markup)
     }
 }
 
@@ -134,4 +420,48 @@ mod tests {
             Some("うつく".into())
         );
     }
+
+    #[test]
+    fn kanji_queries_work() {
+        let index = kanji_index();
+        assert_eq!(index.get('学').unwrap().literal, '学');
+
+        let query = KanjiQuery {
+            grade: Some(Grade::Kyouiku(3)),
+            strokes: Some(8),
+            ..Default::default()
+        };
+        let found = index.query(&query);
+        assert!(found.iter().any(|kanji| kanji.literal == '取'));
+        assert!(found.iter().all(|kanji| kanji.stroke_counts.accepted == 8));
+
+        let query = KanjiQuery {
+            radical: Some(75),
+            max_frequency: Some(300),
+            ..Default::default()
+        };
+        let found: Vec<char> = index.query(&query).iter().map(|k| k.literal).collect();
+        assert_eq!(found.first(), Some(&'本'));
+        assert!(found.contains(&'校'));
+        assert!(!found.contains(&'杯'));
+    }
+
+    #[test]
+    fn prebuilt_kanji_index_matches() {
+        let xml = data::read_xml(&data::data_dir().join(data::KANJIDIC_FILE)).unwrap();
+        let (characters, _, _) = parse_kanjidic(&xml).unwrap();
+        let table = build_table(&characters, true, 0).unwrap();
+        let prebuilt = KanjiIndex::from_table(Table::from_bytes(table).unwrap());
+        assert_eq!(prebuilt.len(), characters.len());
+        for kanji in &characters {
+            assert_eq!(prebuilt.get(kanji.literal), Some(kanji));
+        }
+
+        let query = KanjiQuery {
+            max_frequency: Some(300),
+            ..Default::default()
+        };
+        let in_memory = KanjiIndex::new(characters);
+        assert_eq!(prebuilt.query(&query), in_memory.query(&query));
+    }
 }
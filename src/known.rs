@@ -0,0 +1,59 @@
+//! Words the learner already knows, e.g. exported from their SRS deck.
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::kanji::is_kanji;
+
+#[derive(Clone, Debug, Default)]
+pub struct KnownWords {
+    words: HashSet<String>,
+}
+
+impl KnownWords {
+    /// Reads a list with one word per line. Anything after a tab is ignored, so tab separated
+    /// deck exports can be used as they are, and lines starting with `#` are comments.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read known words from '{}'", path.display()))?;
+        Ok(content
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split('\t').next())
+            .map(str::trim)
+            .filter(|word| !word.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    pub fn contains(&self, word: &str) -> bool {
+        self.words.contains(word)
+    }
+
+    /// Kanji used in any of the words.
+    pub fn kanji(&self) -> HashSet<char> {
+        self.words
+            .iter()
+            .flat_map(|word| word.chars())
+            .filter(|&c| is_kanji(c))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+}
+
+impl FromIterator<String> for KnownWords {
+    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
+        Self {
+            words: iter.into_iter().collect(),
+        }
+    }
+}
@@ -1,11 +1,26 @@
 use pyo3::prelude::*;
 
+pub mod archive;
 pub mod args;
+pub mod cache;
+pub mod concordance;
+pub mod corpus;
+pub mod coverage;
+pub mod data;
 pub mod dedup;
 pub mod dict;
 pub mod document;
+pub mod furigana;
+pub mod index;
 pub mod kanji;
+pub mod known;
+pub mod metadata;
 pub mod nlp;
+pub mod pipeline;
+pub mod prebuilt;
+pub mod query;
+pub mod ranking;
+pub mod repeats;
 pub mod srs;
 pub mod subs;
 
@@ -1,16 +1,35 @@
 use clap::Parser;
 use jmdict::GlossLanguage;
+use kanjidic_types::{kunyomi::KunyomiKind, Grade, Kunyomi, Reading};
 use omoide::{
     args::*,
-    dedup::DocumentDedupSet,
-    document::{Document, DocumentChunk},
+    cache::AnalysisCache,
+    concordance::{self, Hit, KwicFormat},
+    corpus::{self, CorpusFile, CorpusOptions, IngestReport},
+    coverage::{self, KanjiCounts},
+    data,
+    dedup::{DocumentDedupSet, Inserted, Signature},
+    dict,
+    document::Document,
+    furigana::{self, FuriganaFormat, FuriganaPolicy},
+    index::{CorpusIndex, Posting, TermKind, INDEX_FILE},
+    kanji,
+    known::KnownWords,
+    metadata::EpisodeMetadata,
     nlp::{self, Morphology, WordRole},
+    pipeline,
+    query::Query,
+    ranking,
+    repeats::{RepeatConfig, RepeatDetector, RepeatedLines},
     srs::{Memo, Rating},
     subs::parse_subtitle_file,
 };
-use std::time::Duration;
-use std::{collections::HashMap, fs};
-use std::{iter, path::Path, usize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::usize;
 
 fn inspect(memo: &Memo) {
     let secs = memo.next_review(0.9).as_secs();
@@ -101,75 +120,395 @@ pub async fn manage(args: &ManageArgs) -> anyhow::Result<()> {
     if args.download {
         println!("I should download some subtitles");
     }
+    if let Some(cache_dir) = &args.prune_cache {
+        let nlp_engine = nlp::Engine::init().await;
+        let removed = AnalysisCache::open(cache_dir, &nlp_engine)?.prune()?;
+        println!("Removed {removed} stale cache entries");
+    }
+    if let Some(path) = &args.import_jmdict {
+        print_data_info("JMdict", &data::import_jmdict(path)?);
+    }
+    if let Some(path) = &args.import_kanjidic {
+        print_data_info("KANJIDIC", &data::import_kanjidic(path)?);
+    }
+    if args.build_index || args.import_jmdict.is_some() || args.import_kanjidic.is_some() {
+        // one missing doesn't stop the other being built
+        for (name, built) in [
+            ("JMdict", dict::build_index()),
+            ("KANJIDIC", kanji::build_index()),
+        ] {
+            match built {
+                Ok(entries) => println!("Prebuilt {name}, {entries} entries"),
+                Err(e) => println!("Couldn't prebuild {name}: {e:#}"),
+            }
+        }
+    }
+    if args.verify_data {
+        let mut failed = false;
+        for (name, verified) in [
+            ("JMdict", data::verify_jmdict()),
+            ("KANJIDIC", data::verify_kanjidic()),
+        ] {
+            match verified {
+                Ok(info) => println!("{name}: OK, {} entries", info.entries),
+                Err(e) => {
+                    println!("{name}: {e:#}");
+                    failed = true;
+                }
+            }
+        }
+        anyhow::ensure!(
+            !failed,
+            "Some dictionaries in '{}' are unusable",
+            data::data_dir().display()
+        );
+    }
+    if args.data_info {
+        println!("Data directory: {}", data::data_dir().display());
+        for (name, file) in [
+            ("JMdict", data::JMDICT_FILE),
+            ("KANJIDIC", data::KANJIDIC_FILE),
+        ] {
+            match data::info(file) {
+                Some(info) => print_data_info(name, &info),
+                None => println!("{name}: not imported"),
+            }
+        }
+    }
     Ok(())
 }
 
-pub async fn retrieve_and_analyze_subs(subtitles_dir: &Path) -> anyhow::Result<DocumentDedupSet> {
-    if subtitles_dir.exists() {
-        let nlp_engine = nlp::Engine::init().await;
+fn print_data_info(name: &str, info: &data::DataInfo) {
+    println!("{name}:");
+    println!(
+        "  Version: {}",
+        info.version.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "  Created: {}",
+        info.created.as_deref().unwrap_or("unknown")
+    );
+    println!("  Entries: {}", info.entries);
+    println!("  Imported from: {}", info.source.display());
+    let days_ago = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| {
+            now.as_secs().saturating_sub(info.imported) / (24 * 60 * 60)
+        });
+    println!("  Imported: {days_ago} days ago");
+}
 
-        let mut docs = DocumentDedupSet::new();
-
-        for entry in fs::read_dir(subtitles_dir)?.filter_map(|x| x.ok()) {
-            if entry.file_type()?.is_file() {
-                let parsed = parse_subtitle_file(entry.path());
-                match parsed {
-                    Ok(content) => {
-                        let doc = Document::new_with_source(
-                            content.into_iter().map(|v| v.into()).collect(),
-                            entry.path(),
-                        );
-
-                        if let Some(idx) = docs.insert(&nlp_engine, doc).await? {
-                            println!("Processing: {}", entry.file_name().to_string_lossy());
-                            docs[idx].analyze(&nlp_engine).await?;
-                        } else {
-                            println!(
-                                "Skipping as duplicate: {}",
-                                entry.file_name().to_string_lossy()
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        anyhow::bail!("Error in {}:\n{}", entry.path().display(), e);
-                    }
-                };
+/// Reads and parses every subtitle file in the corpus, without running them through NLP yet.
+pub fn load_corpus(args: &CorpusArgs, report: &mut IngestReport) -> anyhow::Result<Vec<Document>> {
+    let groups = corpus::discover(
+        &args.subtitles_dir,
+        &CorpusOptions::from_args(args)?,
+        report,
+    )?;
+    let mut files: Vec<CorpusFile> = groups.into_iter().flat_map(|group| group.files).collect();
+    let parsed = pipeline::parallel_map(&mut files, |file| file.parse());
+    let mut docs = vec![];
+
+    for (file, parsed) in files.into_iter().zip(parsed) {
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                report.fail(&file.path, format!("{e:#}"), vec![]);
+                continue;
             }
+        };
+        if parsed.chunks.is_empty() {
+            report.fail(&file.path, "no subtitles found", parsed.issues);
+            continue;
         }
+        report.partial(&file.path, parsed.issues);
+        report.ingested += 1;
+
+        docs.push(Document::new_with_source(
+            parsed.chunks.into_iter().map(|v| v.into()).collect(),
+            file.path,
+        ));
+    }
+
+    Ok(docs)
+}
+
+fn file_name_of(path: Option<&Path>) -> String {
+    path.and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Sets up deduplication, picking up where the last run over the corpus left off unless the
+/// cache is turned off. `present` has the content hashes of every document in the corpus, and
+/// whatever else the last run saw is forgotten. Also returns where to save it once done.
+fn open_dedup_set(
+    args: &CorpusArgs,
+    present: &HashSet<u64>,
+) -> anyhow::Result<(DocumentDedupSet, Option<PathBuf>)> {
+    let mut docs = DocumentDedupSet::from_args(&args.dedup)?;
+    if args.no_cache {
+        return Ok((docs, None));
+    }
+    let path = args.cache_dir.join("dedup.bin");
+    docs.load(&path, present);
+    Ok((docs, Some(path)))
+}
+
+/// Caches the NLP results of a document. Failing to is only worth a warning, the document just
+/// gets analyzed again next time.
+fn store_in_cache(cache: Option<&AnalysisCache>, doc: &Document) {
+    if let Some(Err(e)) = cache.map(|cache| cache.store(doc)) {
+        eprintln!("Couldn't cache the analysis: {e:#}");
+    }
+}
+
+/// Gets documents ready to be deduplicated: tokenizes the ones that need it, if there's an
+/// engine, and works out their signatures across threads.
+async fn sign_docs(
+    docs: &DocumentDedupSet,
+    input: &mut [Document],
+    nlp_engine: Option<&nlp::Engine>,
+    cache: Option<&AnalysisCache>,
+) -> anyhow::Result<Vec<Signature>> {
+    if let Some(nlp_engine) = nlp_engine {
+        let to_tokenize = input
+            .iter_mut()
+            .filter(|doc| docs.needs_tokenization(doc) && doc.tokenization().is_none())
+            .collect();
+        pipeline::tokenize(nlp_engine, to_tokenize, |doc| {
+            store_in_cache(cache, doc);
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(pipeline::parallel_map(input, |doc| docs.signature(doc)))
+}
+
+/// Deduplicates documents, against each other and the ones kept by earlier runs, saying what's
+/// kept and what's skipped. `present` has the content hashes of every document in the corpus,
+/// which can be more than `input`. Also returns where to save the set once done.
+async fn dedup_docs(
+    args: &CorpusArgs,
+    mut input: Vec<Document>,
+    present: &HashSet<u64>,
+    nlp_engine: Option<&nlp::Engine>,
+    cache: Option<&AnalysisCache>,
+) -> anyhow::Result<(DocumentDedupSet, Option<PathBuf>)> {
+    let (mut docs, dedup_path) = open_dedup_set(args, present)?;
+    if let Some(cache) = cache {
+        pipeline::parallel_map(&mut input, |doc| cache.restore(doc));
+    }
+    let signatures = sign_docs(&docs, &mut input, nlp_engine, cache).await?;
+
+    let group_of = |doc: &Document| doc.source().and_then(Path::parent).map(Path::to_path_buf);
+    let multiple_groups = input.iter().map(group_of).collect::<HashSet<_>>().len() > 1;
+    let mut last_group = None;
+
+    for (doc, signature) in input.into_iter().zip(signatures) {
+        let group = group_of(&doc);
+        if multiple_groups && group != last_group {
+            if let Some(dir) = group.as_deref().and_then(Path::file_name) {
+                println!("Show: {}", dir.to_string_lossy());
+            }
+            last_group = group;
+        }
+        let file_name = file_name_of(doc.source());
+
+        match docs.insert_signed(doc, signature) {
+            Inserted::New(_) => println!("Processing: {file_name}"),
+            Inserted::Replaced(_) => {
+                let replaced = docs.duplicates().last().unwrap();
+                println!(
+                    "Processing: {file_name} (replacing duplicate {})",
+                    file_name_of(replaced.summary.source.as_deref())
+                );
+            }
+            Inserted::Duplicate(kept) => {
+                println!(
+                    "Skipping as duplicate of {}: {file_name}",
+                    file_name_of(docs.summary(kept).source.as_deref())
+                );
+            }
+        }
+    }
+    Ok((docs, dedup_path))
+}
+
+/// Tokenizes, deduplicates and analyses documents, reusing cached results where possible.
+/// `present` has the content hashes of every document in the corpus, which can be more than
+/// `input`.
+pub async fn analyze_docs(
+    args: &CorpusArgs,
+    nlp_engine: &nlp::Engine,
+    input: Vec<Document>,
+    present: &HashSet<u64>,
+) -> anyhow::Result<DocumentDedupSet> {
+    let cache = match args.no_cache {
+        true => None,
+        false => Some(AnalysisCache::open(&args.cache_dir, nlp_engine)?),
+    };
+    let (mut docs, dedup_path) =
+        dedup_docs(args, input, present, Some(nlp_engine), cache.as_ref()).await?;
 
-        println!();
+    let to_analyze = docs
+        .docs_mut()
+        .filter(|doc| doc.analysis().is_none())
+        .collect();
+    pipeline::analyze(nlp_engine, to_analyze, |doc| {
+        store_in_cache(cache.as_ref(), doc);
+        Ok(())
+    })
+    .await?;
 
-        Ok(docs)
-    } else {
-        anyhow::bail!("Directory not found");
+    if let Some(path) = dedup_path {
+        docs.save(&path)?;
+    }
+    Ok(docs)
+}
+
+fn print_report(report: &IngestReport) {
+    println!();
+    if !report.problems.is_empty() {
+        println!("{report}\n");
+    }
+}
+
+pub async fn retrieve_and_analyze_subs(args: &CorpusArgs) -> anyhow::Result<DocumentDedupSet> {
+    let mut report = IngestReport::default();
+    let input = load_corpus(args, &mut report)?;
+    let nlp_engine = nlp::Engine::init().await;
+    let present = input.iter().map(Document::content_hash).collect();
+    let docs = analyze_docs(args, &nlp_engine, input, &present).await?;
+    print_report(&report);
+
+    Ok(docs)
+}
+
+fn print_top_words(occurrences: HashMap<String, usize>, n: usize) {
+    let mut occurrences: Vec<(String, usize)> = occurrences.into_iter().collect();
+    occurrences.sort_by(|a, b| b.1.cmp(&a.1));
+
+    for (word, count) in occurrences.iter().take(n) {
+        println!("  {word}: {count}");
+    }
+}
+
+/// Says how many of the most frequent kanji cover most of the text, and lists them all if
+/// `list`.
+fn print_kanji_coverage(counts: &KanjiCounts, known: &HashSet<char>, list: bool) {
+    let report = counts.report(kanji::kanji_level, known);
+    let describe = |level: Option<u8>| level.map_or("-".to_string(), |level| level.to_string());
+    if list {
+        println!("  rank  kanji  count  coverage  grade  jlpt  known");
+        for (i, kanji) in report.iter().enumerate() {
+            println!(
+                "  {:>4}  {:<5}  {:>5}  {:>7.2}%  {:>5}  {:>4}  {}",
+                i + 1,
+                kanji.kanji,
+                kanji.count,
+                kanji.coverage * 100.0,
+                describe(kanji.level.grade),
+                describe(kanji.level.jlpt),
+                if kanji.known { "yes" } else { "" }
+            );
+        }
+    }
+    for milestone in coverage::MILESTONES {
+        if let Some(needed) = coverage::kanji_needed(&report, milestone) {
+            let unknown = report[..needed].iter().filter(|kanji| !kanji.known).count();
+            print!(
+                "  Learning the {needed} most frequent kanji covers {:.0}% of kanji occurrences",
+                milestone * 100.0
+            );
+            match known.is_empty() {
+                true => println!(),
+                false => println!(" ({unknown} of them new to you)"),
+            }
+        }
     }
 }
 
 pub async fn stats(args: &StatsArgs) -> anyhow::Result<()> {
     let mut occurrences: HashMap<String, usize> = HashMap::new();
+    let mut occurrences_by_show: BTreeMap<String, HashMap<String, usize>> = BTreeMap::new();
+    let mut kanji_counts = KanjiCounts::new();
+    let mut kanji_counts_by_show: BTreeMap<String, KanjiCounts> = BTreeMap::new();
+    // loaded up front, so a bad path fails before the corpus is analyzed
+    let known = args
+        .known_words
+        .as_deref()
+        .map(KnownWords::load)
+        .transpose()?
+        .map(|known| known.kanji())
+        .unwrap_or_default();
 
-    if args.subtitles_dir.exists() {
-        let analyzed = retrieve_and_analyze_subs(&args.subtitles_dir)
+    if args.corpus.subtitles_dir.exists() {
+        let analyzed: Vec<Document> = retrieve_and_analyze_subs(&args.corpus)
             .await?
-            .into_docs();
+            .into_docs()
+            .collect();
+        let repeated = match args.repeats.exclude_repeated {
+            true => {
+                let config = RepeatConfig::from_args(&args.repeats)?;
+                let repeated = RepeatDetector::find_in_documents(&analyzed, config);
+                println!("Leaving out {} repeated lines\n", repeated.len());
+                repeated
+            }
+            false => RepeatedLines::default(),
+        };
 
-        for doc in analyzed {
-            for analysis in doc.analysis().unwrap() {
+        for (doc_i, doc) in analyzed.iter().enumerate() {
+            let show = doc.metadata().show.clone().unwrap_or_default();
+            for (chunk_i, analysis) in doc.analysis().unwrap().iter().enumerate() {
+                if repeated.contains(doc_i as u32, chunk_i as u32) {
+                    continue;
+                }
                 let morphology = nlp::Morphology::from_analysis(analysis.clone());
                 for word in morphology.words() {
+                    if args.kanji_coverage {
+                        kanji_counts.add(&word.text);
+                        if args.by_show {
+                            kanji_counts_by_show
+                                .entry(show.clone())
+                                .or_default()
+                                .add(&word.text);
+                        }
+                    }
                     if word.lookup(false).is_some() {
                         *occurrences.entry(word.lemma().clone()).or_insert(0) += 1;
+                        if args.by_show {
+                            *occurrences_by_show
+                                .entry(show.clone())
+                                .or_default()
+                                .entry(word.lemma())
+                                .or_insert(0) += 1;
+                        }
                     }
                 }
             }
         }
 
-        let mut occurrences: Vec<(String, usize)> = occurrences.into_iter().collect();
-        occurrences.sort_by(|a, b| b.1.cmp(&a.1));
+        for (show, occurrences) in occurrences_by_show {
+            println!("Top 50 words in {show}:");
+            print_top_words(occurrences, 50);
+            println!();
+        }
 
         println!("Top 250 words:");
-        for (word, count) in occurrences.iter().take(250) {
-            println!("  {word}: {count}");
+        print_top_words(occurrences, 250);
+
+        if args.kanji_coverage {
+            for (show, counts) in &kanji_counts_by_show {
+                println!("\nKanji in {show} ({} different):", counts.len());
+                print_kanji_coverage(counts, &known, false);
+            }
+            println!(
+                "\nKanji coverage ({} different kanji, {} occurrences):",
+                kanji_counts.len(),
+                kanji_counts.total()
+            );
+            print_kanji_coverage(&kanji_counts, &known, true);
         }
     }
     Ok(())
@@ -187,47 +526,351 @@ pub async fn analyze(args: AnalysisArgs) -> anyhow::Result<()> {
     process_sentences(sentences).await
 }
 
+/// Brings the corpus index up to date with the documents in the corpus, forgetting the ones that
+/// are gone. Only runs the NLP engine if some of them haven't been indexed yet, or the index was
+/// built by another version of it.
+pub async fn update_index(args: &CorpusArgs, docs: Vec<Document>) -> anyhow::Result<CorpusIndex> {
+    let index_path = args.cache_dir.join(INDEX_FILE);
+    let mut index = match args.no_cache {
+        true => None,
+        false => CorpusIndex::load(&index_path),
+    }
+    .unwrap_or_default();
+
+    let present: HashSet<u64> = docs.iter().map(Document::content_hash).collect();
+    let pruned = index.prune(&present);
+    // a run that didn't need the index may have found the engine was upgraded since
+    let outdated =
+        AnalysisCache::last_engine(&args.cache_dir).is_some_and(|engine| engine != index.engine());
+    if !outdated && docs.iter().all(|doc| index.contains(doc.content_hash())) {
+        if pruned && !args.no_cache {
+            index.save(&index_path)?;
+        }
+        return Ok(index);
+    }
+
+    let nlp_engine = nlp::Engine::init().await;
+    index.ensure_engine(nlp_engine.version());
+    let nlp_engine = &nlp_engine;
+    index_new_docs(&mut index, docs, |new_docs, present| async move {
+        analyze_docs(args, nlp_engine, new_docs, &present).await
+    })
+    .await?;
+
+    if !args.no_cache {
+        index.save(&index_path)?;
+    }
+    Ok(index)
+}
+
+/// Indexes the documents the index hasn't seen yet, once `analyze` has deduplicated and
+/// analysed them. It's given the content hashes of the whole corpus too, so new documents are
+/// deduplicated against the ones already indexed, and indexed ones they replace are dropped.
+async fn index_new_docs<F, Fut>(
+    index: &mut CorpusIndex,
+    docs: Vec<Document>,
+    analyze: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(Vec<Document>, HashSet<u64>) -> Fut,
+    Fut: Future<Output = anyhow::Result<DocumentDedupSet>>,
+{
+    let present: HashSet<u64> = docs.iter().map(Document::content_hash).collect();
+    let new_docs: Vec<Document> = docs
+        .into_iter()
+        .filter(|doc| !index.contains(doc.content_hash()))
+        .collect();
+    let new_hashes: Vec<u64> = new_docs.iter().map(Document::content_hash).collect();
+
+    let docs = analyze(new_docs, present).await?;
+    // earlier runs may have indexed a release that a better one has now replaced
+    let dropped: HashSet<u64> = docs
+        .duplicates()
+        .iter()
+        .map(|duplicate| duplicate.summary.content_hash)
+        .collect();
+    index.remove(&dropped);
+    for doc in docs.docs() {
+        index.add(doc);
+    }
+    // duplicates aren't indexed, but shouldn't count as new next time either
+    for hash in new_hashes {
+        index.mark_seen(hash);
+    }
+    Ok(())
+}
+
+fn format_timestamp(start: Option<Duration>) -> String {
+    match start {
+        Some(start) => format!("[{:02}m{:02}s]", start.as_secs() / 60, start.as_secs() % 60),
+        None => " ".repeat(8),
+    }
+}
+
+/// Puts the hits in the order the user asked for: best first, or as they appear in the corpus.
+fn order_hits(
+    index: &CorpusIndex,
+    mut hits: Vec<Hit>,
+    args: &RankingArgs,
+) -> anyhow::Result<Vec<Hit>> {
+    if !args.in_order {
+        let known = args
+            .known_words
+            .as_deref()
+            .map(KnownWords::load)
+            .transpose()?;
+        return Ok(ranking::rank(index, hits, known.as_ref()));
+    }
+
+    let metadata: HashMap<u32, EpisodeMetadata> = hits
+        .iter()
+        .map(|hit| {
+            let metadata = index
+                .document(hit.doc)
+                .source
+                .as_deref()
+                .map(EpisodeMetadata::from_path)
+                .unwrap_or_default();
+            (hit.doc, metadata)
+        })
+        .collect();
+    hits.sort_by(|a, b| {
+        metadata[&a.doc]
+            .cmp_episode(&metadata[&b.doc])
+            .then_with(|| {
+                index
+                    .document(a.doc)
+                    .source
+                    .cmp(&index.document(b.doc).source)
+            })
+            .then(a.doc.cmp(&b.doc))
+            .then(a.chunk.cmp(&b.chunk))
+    });
+    Ok(hits)
+}
+
+/// Prints the hits as a concordance, up to `max` of them, noting which episode they're from
+/// whenever that changes.
+fn print_matches(
+    index: &CorpusIndex,
+    hits: Vec<Hit>,
+    max: Option<usize>,
+    options: &ConcordanceArgs,
+) {
+    let format = KwicFormat {
+        width: options.width,
+        color: std::io::stdout().is_terminal(),
+    };
+    // context lines start where the matched line's left column does
+    let context_indent = " ".repeat(options.width + 1);
+
+    let mut last_doc = None;
+    for hit in hits.into_iter().take(max.unwrap_or(usize::MAX)) {
+        let doc = index.document(hit.doc);
+        if last_doc != Some(hit.doc) {
+            println!(
+                "Found in {} ({}):",
+                doc.source
+                    .as_deref()
+                    .map(EpisodeMetadata::from_path)
+                    .unwrap_or_default(),
+                doc.source
+                    .as_deref()
+                    .and_then(Path::file_name)
+                    .unwrap_or_default()
+                    .to_string_lossy()
+            );
+            last_doc = Some(hit.doc);
+        }
+        let i = hit.chunk as usize;
+        let context = |range: std::ops::Range<usize>| {
+            for chunk in &doc.chunks[range] {
+                println!(
+                    "  {} {context_indent}{}",
+                    format_timestamp(chunk.start),
+                    concordance::single_line(&chunk.text)
+                );
+            }
+        };
+
+        context(i.saturating_sub(options.context)..i);
+        let chunk = &doc.chunks[i];
+        println!(
+            "  {} {}",
+            format_timestamp(chunk.start),
+            format.line(&concordance::single_line(&chunk.text), hit.span)
+        );
+        context(i + 1..(i + 1 + options.context).min(doc.chunks.len()));
+        if options.context > 0 {
+            println!();
+        }
+    }
+}
+
+/// Loads the corpus and brings its index up to date. Also returns the content hashes of the
+/// documents in the corpus, since the index may also hold ones from other corpora, or files
+/// since removed.
+async fn load_index(args: &CorpusArgs) -> anyhow::Result<(CorpusIndex, HashSet<u64>)> {
+    let mut report = IngestReport::default();
+    let docs = load_corpus(args, &mut report)?;
+    let in_corpus: HashSet<u64> = docs.iter().map(Document::content_hash).collect();
+    let index = update_index(args, docs).await?;
+    print_report(&report);
+    Ok((index, in_corpus))
+}
+
+/// Lines repeated across episodes, if the user asked for them to be left out.
+fn repeated_lines(
+    index: &CorpusIndex,
+    in_corpus: &HashSet<u64>,
+    args: &RepeatArgs,
+) -> anyhow::Result<RepeatedLines> {
+    if !args.exclude_repeated {
+        return Ok(RepeatedLines::default());
+    }
+    let config = RepeatConfig::from_args(args)?;
+    Ok(RepeatDetector::find_in_index(
+        index,
+        |hash| in_corpus.contains(&hash),
+        config,
+    ))
+}
+
 pub async fn examples(args: ExampleArgs) -> anyhow::Result<()> {
-    let analyzed = retrieve_and_analyze_subs(&args.subtitles_dir)
-        .await?
-        .into_docs();
-    let mut found = 0usize;
-
-    for doc in analyzed {
-        let mut found_in_file = false;
-
-        for (analyzed_sentence, chunk) in iter::zip(doc.analysis().unwrap(), doc.chunks()) {
-            let morphology = nlp::Morphology::from_analysis(analyzed_sentence.clone());
-
-            if morphology
-                .words()
-                .find(|word| word.lemma() == args.word)
-                .is_some()
-            {
-                if !found_in_file {
-                    println!(
-                        "Found in {}:",
-                        doc.source().unwrap().file_name().unwrap().to_string_lossy()
-                    );
-                    found_in_file = true;
-                }
-                found += 1;
-                if let DocumentChunk::Subs(sub) = chunk {
-                    println!(
-                        "  [{}] {}",
-                        format!(
-                            "{:02}m{:02}s",
-                            sub.start.as_secs() / 60,
-                            sub.start.as_secs() % 60
-                        ),
-                        sub.content
-                    );
-                }
+    let (index, in_corpus) = load_index(&args.corpus).await?;
+    let repeated = repeated_lines(&index, &in_corpus, &args.repeats)?;
+
+    let mut postings: Vec<Posting> = index
+        .lookup(args.match_on, &args.word)
+        .iter()
+        .filter(|posting| in_corpus.contains(&index.document(posting.doc).content_hash))
+        .filter(|posting| !repeated.contains(posting.doc, posting.chunk))
+        .copied()
+        .collect();
+    // one line per cue, even if the word shows up in it more than once
+    postings.dedup_by_key(|posting| (posting.doc, posting.chunk));
+
+    let hits = postings
+        .into_iter()
+        .map(|posting| {
+            let chunk = &index.document(posting.doc).chunks[posting.chunk as usize];
+            let word = posting.word as usize;
+            let span = concordance::locate(
+                &chunk.text,
+                chunk.words.iter().map(|word| word.surface.as_str()),
+                word..word + 1,
+            )
+            // the engine normalized the text somehow, so show the whole cue as the match
+            .unwrap_or(0..chunk.text.len());
+            Hit {
+                doc: posting.doc,
+                chunk: posting.chunk,
+                span,
             }
-            if let Some(max) = args.max {
-                if found >= max {
-                    return Ok(());
-                }
+        })
+        .collect();
+
+    let hits = order_hits(&index, hits, &args.ranking)?;
+    print_matches(&index, hits, args.max, &args.concordance);
+    Ok(())
+}
+
+pub async fn query(args: QueryArgs) -> anyhow::Result<()> {
+    // bail out on typos before going through the whole corpus
+    let query = Query::parse(&args.pattern)?;
+    let (index, in_corpus) = load_index(&args.corpus).await?;
+    let repeated = repeated_lines(&index, &in_corpus, &args.repeats)?;
+
+    let mut hits = vec![];
+    for (doc_i, doc) in index.documents().iter().enumerate() {
+        if !in_corpus.contains(&doc.content_hash) {
+            continue;
+        }
+        for (chunk_i, chunk) in doc.chunks.iter().enumerate() {
+            if repeated.contains(doc_i as u32, chunk_i as u32) {
+                continue;
+            }
+            // one line per cue, showing the first match in it
+            let Some(range) = query.find(&chunk.units).into_iter().next() else {
+                continue;
+            };
+            let span = concordance::locate(
+                &chunk.text,
+                chunk.units.iter().map(|unit| unit.surface.as_str()),
+                range,
+            )
+            .unwrap_or(0..chunk.text.len());
+            hits.push(Hit {
+                doc: doc_i as u32,
+                chunk: chunk_i as u32,
+                span,
+            });
+        }
+    }
+
+    let hits = order_hits(&index, hits, &args.ranking)?;
+    print_matches(&index, hits, args.max, &args.concordance);
+    Ok(())
+}
+
+pub async fn dedup(args: DuplicatesArgs) -> anyhow::Result<()> {
+    let mut report = IngestReport::default();
+    let mut input = load_corpus(&args.corpus, &mut report)?;
+    let present = input.iter().map(Document::content_hash).collect();
+    let (mut docs, dedup_path) = open_dedup_set(&args.corpus, &present)?;
+
+    // shingling by characters doesn't need the engine at all, nor do files seen in earlier runs
+    let nlp_engine = match input.iter().any(|doc| docs.needs_tokenization(doc)) {
+        true => Some(nlp::Engine::init().await),
+        false => None,
+    };
+    let cache = match (&nlp_engine, args.corpus.no_cache) {
+        (Some(nlp_engine), false) => Some(AnalysisCache::open(&args.corpus.cache_dir, nlp_engine)?),
+        _ => None,
+    };
+
+    if let Some(cache) = &cache {
+        pipeline::parallel_map(&mut input, |doc| {
+            docs.needs_tokenization(doc) && cache.restore(doc)
+        });
+    }
+    let signatures = sign_docs(&docs, &mut input, nlp_engine.as_ref(), cache.as_ref()).await?;
+    for (doc, signature) in input.into_iter().zip(signatures) {
+        docs.insert_signed(doc, signature);
+    }
+    if let Some(path) = dedup_path {
+        docs.save(&path)?;
+    }
+    print_report(&report);
+
+    let display = |path: Option<&Path>| match path {
+        Some(path) => path
+            .strip_prefix(&args.corpus.subtitles_dir)
+            .unwrap_or(path)
+            .display()
+            .to_string(),
+        None => "<unknown>".into(),
+    };
+    let clusters = docs.clusters();
+    println!(
+        "Found {} groups of near-duplicate files, {} files skipped",
+        clusters.len(),
+        docs.duplicates().len()
+    );
+
+    for (i, cluster) in clusters.iter().enumerate() {
+        println!("\nGroup {}:", i + 1);
+        for (j, source) in cluster.sources().enumerate() {
+            let status = match j {
+                0 => "kept",
+                _ => "skipped",
+            };
+            println!("  [{}] {status:<7} {}", j + 1, display(source));
+        }
+        println!("  Estimated similarity:");
+        for (a, row) in cluster.similarities.iter().enumerate() {
+            for (b, similarity) in row.iter().enumerate().skip(a + 1) {
+                println!("    [{}] ~ [{}]: {similarity:.2}", a + 1, b + 1);
             }
         }
     }
@@ -236,39 +879,288 @@ pub async fn examples(args: ExampleArgs) -> anyhow::Result<()> {
 }
 
 pub async fn read_furigana(args: FuriganaArgs) -> anyhow::Result<()> {
+    let mut policy = FuriganaPolicy::from_args(&args)?;
     let nlp_engine = nlp::Engine::init().await;
     let analyzed = nlp_engine
         .morphological_analysis_batch(args.sentence)
         .await?;
 
     for analysis in analyzed {
-        let mut markup = String::new();
         let morphology = Morphology::from_analysis(analysis);
+        let segments: Vec<furigana::Segment> = morphology
+            .words()
+            .flat_map(|word| {
+                let mut segments = word.furigana_segments();
+                policy.apply(&word.text, &word.lemma(), &mut segments, kanji::kanji_level);
+                segments
+            })
+            .collect();
 
-        for word in morphology.words() {
-            if let Some(ruby) = word.ruby_furigana() {
-                markup.push_str(&ruby);
-            } else {
-                markup.push_str(&word.text);
-            }
+        println!(
+            "{}",
+            furigana::render(&segments, args.format, args.mark_guessed)
+        );
+        if args.format != FuriganaFormat::Json {
+            println!();
         }
+    }
 
-        println!("{markup}");
-        println!();
+    Ok(())
+}
+
+fn describe_grade(grade: Grade) -> String {
+    match grade {
+        Grade::Kyouiku(grade) => format!("{grade} (taught in primary school)"),
+        Grade::Jouyou => "jōyō (taught in secondary school)".into(),
+        Grade::Jinmeiyou => "jinmeiyō (used in names)".into(),
+        Grade::JinmeiyouJouyouVariant => "jinmeiyō (variant of a jōyō kanji)".into(),
     }
+}
 
+fn describe_kunyomi(kunyomi: &Kunyomi) -> String {
+    let mut reading = kunyomi.reading.clone();
+    if let Some(okurigana) = &kunyomi.okurigana {
+        reading = format!("{reading}.{okurigana}");
+    }
+    match kunyomi.kind {
+        KunyomiKind::Normal => reading,
+        KunyomiKind::Prefix => format!("{reading}-"),
+        KunyomiKind::Suffix => format!("-{reading}"),
+    }
+}
+
+pub fn kanji(args: KanjiArgs) -> anyhow::Result<()> {
+    let literals: Vec<char> = args.kanji.chars().filter(|&c| kanji::is_kanji(c)).collect();
+    anyhow::ensure!(!literals.is_empty(), "No kanji in '{}'", args.kanji);
+    let index = CorpusIndex::load(&args.cache_dir.join(INDEX_FILE));
+
+    for (i, &literal) in literals.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let Some(kanji) = kanji::kanji_index().get(literal) else {
+            println!("{literal}: not in KANJIDIC");
+            continue;
+        };
+        println!("{literal}");
+        let list = |items: Vec<String>| match items.is_empty() {
+            true => "-".to_string(),
+            false => items.join(", "),
+        };
+        let meanings = kanji.translations.get("en").cloned().unwrap_or_default();
+        println!("  Meanings:  {}", list(meanings));
+        let (mut on, mut kun) = (vec![], vec![]);
+        for reading in &kanji.readings {
+            match reading {
+                Reading::Onyomi(reading) => on.push(reading.clone()),
+                Reading::Kunyomi(reading) => kun.push(describe_kunyomi(reading)),
+                _ => {}
+            }
+        }
+        println!("  On:        {}", list(on));
+        println!("  Kun:       {}", list(kun));
+        println!("  Nanori:    {}", list(kanji.nanori.clone()));
+        println!("  Strokes:   {}", kanji.stroke_counts.accepted);
+        if let Some(grade) = kanji.grade {
+            println!("  Grade:     {}", describe_grade(grade));
+        }
+        if let Some(jlpt) = kanji.jlpt {
+            println!("  JLPT:      level {jlpt} (of the old 4 levels)");
+        }
+        if let Some(frequency) = kanji.frequency {
+            println!("  Frequency: #{frequency} in newspapers");
+        }
+
+        let Some(index) = &index else {
+            continue;
+        };
+        let mut words: Vec<(&str, usize)> = index
+            .terms(TermKind::Lemma)
+            .filter(|(lemma, _)| lemma.contains(literal))
+            .map(|(lemma, postings)| (lemma, postings.len()))
+            .collect();
+        words.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        if !words.is_empty() {
+            println!("  In the corpus:");
+            for (word, count) in words.into_iter().take(args.words) {
+                println!("    {word}: {count}");
+            }
+        }
+    }
+    if index.is_none() {
+        println!(
+            "\nNo corpus index in '{}' to find words in, run `examples` or `query` to make one",
+            args.cache_dir.display()
+        );
+    }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    if let Some(data_dir) = cli.data_dir {
+        data::set_data_dir(data_dir)?;
+    }
     match cli.cmd {
         Some(Commands::Practice) | None => practice().await,
         Some(Commands::Manage(args)) => manage(&args).await,
         Some(Commands::Stats(args)) => stats(&args).await,
         Some(Commands::Analyze(args)) => analyze(args).await,
         Some(Commands::Examples(args)) => examples(args).await,
+        Some(Commands::Query(args)) => query(args).await,
+        Some(Commands::Dedup(args)) => dedup(args).await,
         Some(Commands::Furigana(args)) => read_furigana(args).await,
+        Some(Commands::Kanji(args)) => kanji(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use omoide::{document::DocumentChunk, nlp::Analysis};
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        corpus: CorpusArgs,
+    }
+
+    /// What `update_index` does, with empty analyses standing in for the NLP engine's.
+    async fn index_pass(args: &CorpusArgs, index: &mut CorpusIndex, docs: Vec<Document>) {
+        index_new_docs(index, docs, |new_docs, present| async move {
+            let (mut docs, path) = dedup_docs(args, new_docs, &present, None, None).await?;
+            for doc in docs.docs_mut() {
+                let analysis = doc
+                    .contents()
+                    .map(|_| Analysis {
+                        units: vec![],
+                        deps: vec![],
+                        relations: vec![],
+                    })
+                    .collect();
+                doc.restore(None, Some(analysis));
+            }
+            docs.save(&path.unwrap())?;
+            Ok(docs)
+        })
+        .await
+        .unwrap();
+    }
+
+    const EPISODE: &str = "今日はいい天気ですね。散歩に行きましょうか。それとも映画を見に行く？\
+                           どっちでもいいよ。じゃあ公園に行こう。お弁当も持っていこうか。";
+    const OTHER: &str = "明日は雨が降るらしいから、傘を忘れないでね。学校には遅れないように。";
+
+    /// Corpus arguments with their own cache, since tests run concurrently.
+    fn test_args(name: &str, extra: &[&str]) -> CorpusArgs {
+        let cache_dir = std::env::temp_dir().join(format!("omoide-{name}-{}", std::process::id()));
+        let cache_dir = cache_dir.to_str().unwrap();
+        let args = [
+            "omoide",
+            "-d",
+            cache_dir,
+            "--cache-dir",
+            cache_dir,
+            "--shingle",
+            "chars",
+        ];
+        TestCli::parse_from(args.iter().chain(extra)).corpus
+    }
+
+    fn doc(args: &CorpusArgs, name: &str, chunks: &[&str]) -> Document {
+        let chunks = chunks
+            .iter()
+            .map(|&text| DocumentChunk::Plaintext(text.into()))
+            .collect();
+        Document::new_with_source(chunks, args.cache_dir.join(name))
+    }
+
+    #[tokio::test]
+    async fn later_runs_dedup_against_indexed_files() {
+        let args = test_args("index-runs", &["--keep-first"]);
+        let cache_dir = args.cache_dir.clone();
+        let doc = |name: &str, text: &str| doc(&args, name, &[text]);
+
+        let mut index = CorpusIndex::new("test");
+        index_pass(
+            &args,
+            &mut index,
+            vec![doc("01.srt", EPISODE), doc("02.srt", OTHER)],
+        )
+        .await;
+        assert_eq!(index.documents().len(), 2);
+
+        // a re-release of the first episode, added to the corpus later
+        let rerelease = doc("01 [v2].srt", &format!("{EPISODE}またね。"));
+        let rerelease_hash = rerelease.content_hash();
+        index_pass(
+            &args,
+            &mut index,
+            vec![doc("01.srt", EPISODE), doc("02.srt", OTHER), rerelease],
+        )
+        .await;
+        assert_eq!(index.documents().len(), 2);
+        assert!(index.contains(rerelease_hash));
+
+        // and what the first run kept is still saved
+        let (docs, _) = open_dedup_set(
+            &args,
+            &index
+                .documents()
+                .iter()
+                .map(|doc| doc.content_hash)
+                .chain([rerelease_hash])
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(docs.duplicates().len(), 1);
+        assert_eq!(
+            file_name_of(docs.summary(docs.duplicates()[0].kept).source.as_deref()),
+            "01.srt"
+        );
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn better_releases_replace_indexed_ones() {
+        let args = test_args("index-replaced", &[]);
+        let first = doc(&args, "01.txt", &[EPISODE]);
+        let first_hash = first.content_hash();
+        let mut index = CorpusIndex::new("test");
+        index_pass(
+            &args,
+            &mut index,
+            vec![first, doc(&args, "02.txt", &[OTHER])],
+        )
+        .await;
+        assert!(index
+            .documents()
+            .iter()
+            .any(|doc| doc.content_hash == first_hash));
+
+        // more cues make for a better release
+        let better = doc(&args, "01 [v2].txt", &[EPISODE, "またね。"]);
+        let better_hash = better.content_hash();
+        index_pass(
+            &args,
+            &mut index,
+            vec![
+                doc(&args, "01.txt", &[EPISODE]),
+                doc(&args, "02.txt", &[OTHER]),
+                better,
+            ],
+        )
+        .await;
+        let indexed: HashSet<u64> = index
+            .documents()
+            .iter()
+            .map(|doc| doc.content_hash)
+            .collect();
+        assert_eq!(indexed.len(), 2);
+        assert!(indexed.contains(&better_hash) && !indexed.contains(&first_hash));
+        // and isn't indexed again on the next run
+        assert!(index.contains(first_hash));
+        std::fs::remove_dir_all(&args.cache_dir).unwrap();
     }
 }
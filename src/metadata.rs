@@ -0,0 +1,264 @@
+//! Guess what show and episode a subtitle file belongs to from its file name, e.g.
+//! `[Group] Show Name - 03 [1080p].ja.srt` or `Show.Name.S01E03.1080p.WEB.ja.srt`.
+use std::cmp::Ordering;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref LEADING_GROUP_RE: Regex = Regex::new(r"^\s*[\[(]([^\])]+)[\])]").unwrap();
+    static ref BRACKETED_RE: Regex = Regex::new(r"[\[({【][^\])}】]*[\])}】]").unwrap();
+    static ref LANGUAGE_RE: Regex = Regex::new(
+        r"^(?i:(?:ja|jp|jpn|en|eng|zh|chi|zho|ko|kor|fr|fre|fra|de|ger|deu|es|spa|pt|por|it|ita|ru|rus)(?:[-_][a-z]{2,4})?)$"
+    )
+    .unwrap();
+    static ref SXXEYY_RE: Regex =
+        Regex::new(r"(?i)\bS(\d{1,2})\s*E(\d{1,4})(?:v\d)?\b").unwrap();
+    static ref NXNN_RE: Regex = Regex::new(r"\b(\d{1,2})x(\d{1,4})\b").unwrap();
+    static ref DASH_EPISODE_RE: Regex = Regex::new(r"\s-\s(\d{1,4})(?:v\d)?(?:\s|$)").unwrap();
+    static ref MARKED_EPISODE_RE: Regex =
+        Regex::new(r"(?i)(?:第\s*(\d{1,4})\s*[話回]|#\s*(\d{1,4})\b|\b(?:ep|episode)\.?\s*(\d{1,4})\b)")
+            .unwrap();
+    static ref TRAILING_NUMBER_RE: Regex = Regex::new(r"(?:^|\s)(\d{1,4})(?:v\d)?\s*$").unwrap();
+    static ref SEASON_RE: Regex = Regex::new(
+        r"(?i)(?:\bseason\s*(\d{1,2})\b|\b(\d{1,2})(?:st|nd|rd|th)\s+season\b|\bS(\d{1,2})$|第\s*(\d{1,2})\s*期)"
+    )
+    .unwrap();
+    /// Release tags trailing the title and episode in scene-style names.
+    static ref SCENE_TAGS_RE: Regex = Regex::new(
+        r"(?i)\s(?:\d{3,4}p|x26[45]|h\s?26[45]|hevc|web(?:-?dl|rip)?|bd(?:rip)?|blu-?ray|aac|flac|10bit)\b.*$"
+    )
+    .unwrap();
+}
+
+/// What we could work out about the episode a document was taken from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EpisodeMetadata {
+    pub show: Option<String>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    /// Language tag, e.g. `ja` in `Show - 01.ja.srt`.
+    pub language: Option<String>,
+    pub release_group: Option<String>,
+}
+
+impl EpisodeMetadata {
+    /// Parses the file name of `path`. When no show title can be found in it, the name of the
+    /// folder (or archive) containing the file is used instead.
+    pub fn from_path(path: &Path) -> Self {
+        let folder = path.parent().and_then(Path::file_name).map(|dir| {
+            let dir = dir.to_string_lossy();
+            let lowercase = dir.to_ascii_lowercase();
+            let archive_ext = [".tar.gz", ".tgz", ".tar", ".zip"]
+                .into_iter()
+                .find(|ext| lowercase.ends_with(ext));
+            dir[..dir.len() - archive_ext.map_or(0, str::len)].to_string()
+        });
+        let mut metadata = path
+            .file_name()
+            .map(|name| Self::parse(&name.to_string_lossy(), folder.as_deref()))
+            .unwrap_or_default();
+        if metadata.show.is_none() {
+            metadata.show = folder;
+        }
+        metadata
+    }
+
+    /// Parses a file name on its own. A number just trailing the title is taken to be part of it,
+    /// e.g. `Mob Psycho 100.srt`, unless it follows a season; `from_path` can also tell from the
+    /// folder name.
+    pub fn from_file_name(file_name: &str) -> Self {
+        Self::parse(file_name, None)
+    }
+
+    fn parse(file_name: &str, folder: Option<&str>) -> Self {
+        let mut metadata = Self::default();
+
+        // extension, then an optional language tag before it
+        let mut stem = file_name;
+        if let Some((rest, _)) = stem.rsplit_once('.') {
+            stem = rest;
+        }
+        if let Some((rest, tag)) = stem.rsplit_once('.') {
+            if LANGUAGE_RE.is_match(tag) && !rest.is_empty() {
+                metadata.language = Some(tag.to_lowercase());
+                stem = rest;
+            }
+        }
+
+        if let Some(captures) = LEADING_GROUP_RE.captures(stem) {
+            metadata.release_group = Some(captures[1].trim().to_string());
+            stem = &stem[captures[0].len()..];
+        }
+
+        let mut name = BRACKETED_RE.replace_all(stem, " ").into_owned();
+        // scene-style names use dots or underscores in place of spaces
+        if !name.trim().contains(' ') {
+            name = name.replace(['.', '_'], " ");
+            name = SCENE_TAGS_RE.replace(&name, "").into_owned();
+        }
+
+        let mut title_end = name.len();
+        if let Some(captures) = SXXEYY_RE
+            .captures(&name)
+            .or_else(|| NXNN_RE.captures(&name))
+        {
+            metadata.season = captures[1].parse().ok();
+            metadata.episode = captures[2].parse().ok();
+            title_end = captures.get(0).unwrap().start();
+        } else if let Some(captures) = DASH_EPISODE_RE
+            .captures(&name)
+            .or_else(|| MARKED_EPISODE_RE.captures(&name))
+            .or_else(|| {
+                TRAILING_NUMBER_RE
+                    .captures(&name)
+                    .filter(|captures| is_trailing_episode(&name, captures, folder))
+            })
+        {
+            metadata.episode = captures
+                .iter()
+                .skip(1)
+                .flatten()
+                .next()
+                .and_then(|m| m.as_str().parse().ok());
+            title_end = captures.get(0).unwrap().start();
+        }
+
+        let mut title = name[..title_end].to_string();
+        if let Some(captures) = SEASON_RE.captures(&title) {
+            if metadata.season.is_none() {
+                metadata.season = captures
+                    .iter()
+                    .skip(1)
+                    .flatten()
+                    .next()
+                    .and_then(|m| m.as_str().parse().ok());
+            }
+            title.replace_range(captures.get(0).unwrap().range(), "");
+        }
+
+        let title = title
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .trim_matches(|c: char| c == '-' || c == '_' || c == '.' || c.is_whitespace())
+            .to_string();
+        if !title.is_empty() {
+            metadata.show = Some(title);
+        }
+
+        metadata
+    }
+
+    /// Orders by show, then season (missing seasons count as the first), then episode.
+    pub fn cmp_episode(&self, other: &Self) -> Ordering {
+        self.show
+            .cmp(&other.show)
+            .then(self.season.unwrap_or(1).cmp(&other.season.unwrap_or(1)))
+            .then(self.episode.cmp(&other.episode))
+    }
+}
+
+/// Whether a number trailing a name is its episode rather than the end of the show title: when
+/// it's all there is to the name, when it follows a season, or when the folder name doesn't end in
+/// the same number (`Mob Psycho 100/Mob Psycho 100 05.srt`).
+fn is_trailing_episode(name: &str, captures: &regex::Captures, folder: Option<&str>) -> bool {
+    let before = name[..captures.get(0).unwrap().start()].trim_end();
+    let number: Option<u32> = captures[1].parse().ok();
+    let folder_number = |folder: &str| -> Option<u32> {
+        TRAILING_NUMBER_RE
+            .captures(folder.trim_end())
+            .and_then(|captures| captures[1].parse().ok())
+    };
+    before.is_empty()
+        || SEASON_RE
+            .find_iter(before)
+            .any(|season| season.end() == before.len())
+        || folder.is_some_and(|folder| folder_number(folder) != number)
+}
+
+impl std::fmt::Display for EpisodeMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.show.as_deref().unwrap_or("Unknown show"))?;
+        match (self.season, self.episode) {
+            (Some(season), Some(episode)) => write!(f, " S{season:02}E{episode:02}"),
+            (None, Some(episode)) => write!(f, " #{episode:02}"),
+            (Some(season), None) => write!(f, " S{season:02}"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fansub_names() {
+        let metadata = EpisodeMetadata::from_file_name("[Group] Show Name - 03 [1080p].ja.srt");
+        assert_eq!(metadata.show.as_deref(), Some("Show Name"));
+        assert_eq!(metadata.episode, Some(3));
+        assert_eq!(metadata.season, None);
+        assert_eq!(metadata.language.as_deref(), Some("ja"));
+        assert_eq!(metadata.release_group.as_deref(), Some("Group"));
+    }
+
+    #[test]
+    fn parses_scene_names() {
+        let metadata = EpisodeMetadata::from_file_name("Show.Name.S02E11.1080p.WEB.H264.jpn.srt");
+        assert_eq!(metadata.show.as_deref(), Some("Show Name"));
+        assert_eq!(metadata.season, Some(2));
+        assert_eq!(metadata.episode, Some(11));
+        assert_eq!(metadata.language.as_deref(), Some("jpn"));
+    }
+
+    #[test]
+    fn parses_seasons_in_titles() {
+        let metadata = EpisodeMetadata::from_file_name("Show Name 2nd Season - 05v2.srt");
+        assert_eq!(metadata.show.as_deref(), Some("Show Name"));
+        assert_eq!(metadata.season, Some(2));
+        assert_eq!(metadata.episode, Some(5));
+
+        let metadata = EpisodeMetadata::from_file_name("番組 第12話.srt");
+        assert_eq!(metadata.show.as_deref(), Some("番組"));
+        assert_eq!(metadata.episode, Some(12));
+    }
+
+    #[test]
+    fn keeps_numbers_that_end_titles() {
+        let metadata = EpisodeMetadata::from_file_name("Mob Psycho 100.ja.srt");
+        assert_eq!(metadata.show.as_deref(), Some("Mob Psycho 100"));
+        assert_eq!(metadata.episode, None);
+
+        let metadata = EpisodeMetadata::from_file_name("Steins;Gate 0.srt");
+        assert_eq!(metadata.show.as_deref(), Some("Steins;Gate 0"));
+        assert_eq!(metadata.episode, None);
+
+        let metadata = EpisodeMetadata::from_file_name("Mob Psycho 100 - 05.srt");
+        assert_eq!(metadata.show.as_deref(), Some("Mob Psycho 100"));
+        assert_eq!(metadata.episode, Some(5));
+
+        let metadata = EpisodeMetadata::from_file_name("Show Name Season 2 07.srt");
+        assert_eq!(metadata.show.as_deref(), Some("Show Name"));
+        assert_eq!(metadata.season, Some(2));
+        assert_eq!(metadata.episode, Some(7));
+
+        let metadata =
+            EpisodeMetadata::from_path(Path::new("/anime/Mob Psycho 100/Mob Psycho 100 05.srt"));
+        assert_eq!(metadata.show.as_deref(), Some("Mob Psycho 100"));
+        assert_eq!(metadata.episode, Some(5));
+
+        let metadata =
+            EpisodeMetadata::from_path(Path::new("/anime/Mob Psycho 100/Mob Psycho 100.srt"));
+        assert_eq!(metadata.show.as_deref(), Some("Mob Psycho 100"));
+        assert_eq!(metadata.episode, None);
+    }
+
+    #[test]
+    fn falls_back_to_folder_name() {
+        let metadata = EpisodeMetadata::from_path(Path::new("/anime/Show Name/03.srt"));
+        assert_eq!(metadata.show.as_deref(), Some("Show Name"));
+        assert_eq!(metadata.episode, Some(3));
+    }
+}
@@ -1,14 +1,16 @@
 use pyo3::conversion::FromPyObject;
 use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::iter;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task;
 
-use crate::dict::INDEX_BY_READING;
+use crate::dict;
 use crate::kanji::KANJI_RE;
 
 // TODO: parameterize by categories. tense, politeness, polarity blah blah
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[pyclass]
 pub enum WordRole {
     Verb,
@@ -82,6 +84,23 @@ impl WordRole {
         }
     }
 
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WordRole::Verb => "verb",
+            WordRole::Noun => "noun",
+            WordRole::Adjective => "adjective",
+            WordRole::Adverb => "adverb",
+            WordRole::Pronoun => "pronoun",
+            WordRole::Determiner => "determiner",
+            WordRole::Particle => "particle",
+            WordRole::Conjunction => "conjunction",
+            WordRole::Counter => "counter",
+            WordRole::Copula => "copula",
+            WordRole::Expression => "expression",
+            WordRole::Other => "other",
+        }
+    }
+
     pub fn is_open(&self) -> bool {
         match self {
             WordRole::Verb => true,
@@ -136,7 +155,7 @@ impl Word {
 }
 
 impl Word {
-    pub fn lookup(&self, lookup_closed: bool) -> Option<(jmdict::Entry, String)> {
+    pub fn lookup(&self, lookup_closed: bool) -> Option<(&'static dict::Entry, String)> {
         for n in (1..=self.lemma_units.len()).rev() {
             let merged_reading = self
                 .lemma_units
@@ -144,7 +163,8 @@ impl Word {
                 .take(n)
                 .map(|t| t.lemma.as_str())
                 .collect::<String>();
-            if let Some(entries) = INDEX_BY_READING.get(&merged_reading) {
+            let entries = dict::lookup(&merged_reading);
+            if !entries.is_empty() {
                 if !lookup_closed && !self.role.is_open() {
                     return None;
                 }
@@ -156,7 +176,7 @@ impl Word {
                 });
 
                 if let Some(entry) = entry {
-                    return Some((entry.clone(), merged_reading));
+                    return Some((*entry, merged_reading));
                 } else {
                     return Some((entries[0], merged_reading));
                 }
@@ -227,7 +247,8 @@ impl Morphology {
                         .map(|t| t.unit.as_str())
                         .chain([_unit.lemma.as_str()])
                         .collect::<String>();
-                    if let Some(entries) = INDEX_BY_READING.get(&merged_reading) {
+                    let entries = dict::lookup(&merged_reading);
+                    if !entries.is_empty() {
                         let entry = entries.iter().find(|entry| {
                             entry
                                 .senses()
@@ -315,7 +336,7 @@ impl Morphology {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[pyclass]
 pub enum UposTag {
     Adjective,
@@ -422,7 +443,7 @@ impl UposTag {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[pyclass]
 pub struct WordUnit {
     pub unit: String,
@@ -458,7 +479,7 @@ impl WordUnit {
 impl WordUnit {
     /// Attemps to find this word in the dictionary.
     /// If found, returns the jmdict entry and the matched dictionary form.
-    pub fn lookup(&self, lookup_closed: bool) -> Option<(&jmdict::Entry, &str)> {
+    pub fn lookup(&self, lookup_closed: bool) -> Option<(&dict::Entry, &str)> {
         if self.class.is_open() || lookup_closed {
             let found = self.lookup_with_pos_filter().next();
             if found.is_some() {
@@ -473,7 +494,7 @@ impl WordUnit {
 
     // TODO: index the dictionary for random access
     // TODO: DRY?
-    fn lookup_with_pos_filter(&self) -> impl Iterator<Item = (&jmdict::Entry, &str)> {
+    fn lookup_with_pos_filter(&self) -> impl Iterator<Item = (&dict::Entry, &str)> {
         self.lookup_by_readings().filter(|(entry, _)| {
             entry
                 .senses()
@@ -481,20 +502,19 @@ impl WordUnit {
         })
     }
 
-    fn lookup_by_readings(&self) -> impl Iterator<Item = (&jmdict::Entry, &str)> {
-        let (reading, entries) = match crate::dict::INDEX_BY_READING.get_key_value(&self.lemma) {
-            Some((reading, entries)) => (reading.as_str(), entries.as_slice()),
-            None => ("", Default::default()),
-        };
-        entries.iter().map(move |e| (e, reading))
+    fn lookup_by_readings(&self) -> impl Iterator<Item = (&dict::Entry, &str)> {
+        let reading = self.lemma.as_str();
+        dict::lookup(reading).into_iter().map(move |e| (e, reading))
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[pyclass]
 pub struct Analysis {
     pub units: Vec<WordUnit>,
     pub deps: Vec<usize>,
+    /// Dependency relation of each unit to its head, e.g. `obj` or `nsubj`.
+    pub relations: Vec<String>,
 }
 
 impl From<AnalysisRaw> for Analysis {
@@ -502,6 +522,7 @@ impl From<AnalysisRaw> for Analysis {
         Self {
             units: value.units,
             deps: value.deps,
+            relations: value.relations,
         }
     }
 }
@@ -509,8 +530,13 @@ impl From<AnalysisRaw> for Analysis {
 #[pymethods]
 impl Analysis {
     #[new]
-    fn py_new(units: Vec<WordUnit>, deps: Vec<usize>) -> Self {
-        Self { units, deps }
+    #[pyo3(signature = (units, deps, relations = vec![]))]
+    fn py_new(units: Vec<WordUnit>, deps: Vec<usize>, relations: Vec<String>) -> Self {
+        Self {
+            units,
+            deps,
+            relations,
+        }
     }
 }
 
@@ -518,6 +544,7 @@ impl Analysis {
 pub struct AnalysisRaw {
     pub units: Vec<WordUnit>,
     pub deps: Vec<usize>,
+    pub relations: Vec<String>,
 }
 
 impl<'py> FromPyObject<'py> for AnalysisRaw {
@@ -540,14 +567,20 @@ impl<'py> FromPyObject<'py> for AnalysisRaw {
             .collect();
 
         let deps: Vec<usize> = ob.get_item(3)?.extract()?;
+        let relations: Vec<String> = ob.get_item(4)?.extract()?;
 
-        Ok(Self { units, deps })
+        Ok(Self {
+            units,
+            deps,
+            relations,
+        })
     }
 }
 
 pub struct Engine {
     _handle: task::JoinHandle<()>,
     tx: mpsc::UnboundedSender<EngineCommand>,
+    version: String,
 }
 
 enum EngineCommand {
@@ -555,7 +588,7 @@ enum EngineCommand {
     Tokenize(Vec<String>, oneshot::Sender<DocumentTokenization>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[pyclass]
 pub struct DocumentTokenization {
     pub tokenization: Vec<Vec<String>>, // TODO: holy allocations...? those strings are very small
@@ -570,7 +603,8 @@ impl Engine {
         let _handle = task::spawn_blocking(move || {
             let done: anyhow::Result<()> = Python::with_gil(|py| {
                 let nlp = PyModule::from_code_bound(py, include_str!("nlp.py"), "nlp.py", "nlp")?;
-                init_tx.send(()).unwrap();
+                let version: String = nlp.getattr("version")?.call0()?.extract()?;
+                init_tx.send(version).unwrap();
                 loop {
                     if let Some(cmd) = rx.blocking_recv() {
                         match cmd {
@@ -599,8 +633,18 @@ impl Engine {
             done.unwrap()
         });
 
-        init_rx.await.unwrap();
-        Self { _handle, tx }
+        let version = init_rx.await.unwrap();
+        Self {
+            _handle,
+            tx,
+            version,
+        }
+    }
+
+    /// Identifies the spaCy version and models in use, anything derived from their output
+    /// should be thrown away when this changes.
+    pub fn version(&self) -> &str {
+        &self.version
     }
 
     pub async fn morphological_analysis(&self, input: String) -> anyhow::Result<Analysis> {
@@ -611,21 +655,39 @@ impl Engine {
         Ok(morphologies.pop().unwrap())
     }
 
-    pub async fn morphological_analysis_batch(
+    /// The sentences are queued up with the engine straight away rather than when first
+    /// awaited, so more can be queued while it works through them.
+    pub fn morphological_analysis_batch(
         &self,
         input: Vec<String>,
-    ) -> anyhow::Result<Vec<Analysis>> {
+    ) -> impl Future<Output = anyhow::Result<Vec<Analysis>>> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(EngineCommand::Analyze(input.into(), tx))?;
-        let morphologies = rx.await?;
-        Ok(morphologies)
+        let sent = self
+            .tx
+            .send(EngineCommand::Analyze(input, tx))
+            .map_err(anyhow::Error::from);
+        async move {
+            sent?;
+            let morphologies = rx.await?;
+            Ok(morphologies)
+        }
     }
 
-    pub async fn tokenize_batch(&self, input: Vec<String>) -> anyhow::Result<DocumentTokenization> {
+    /// Queued up straight away, like `morphological_analysis_batch`.
+    pub fn tokenize_batch(
+        &self,
+        input: Vec<String>,
+    ) -> impl Future<Output = anyhow::Result<DocumentTokenization>> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(EngineCommand::Tokenize(input, tx))?;
-        let tokenized = rx.await?;
-        Ok(tokenized)
+        let sent = self
+            .tx
+            .send(EngineCommand::Tokenize(input, tx))
+            .map_err(anyhow::Error::from);
+        async move {
+            sent?;
+            let tokenized = rx.await?;
+            Ok(tokenized)
+        }
     }
 }
 
@@ -633,7 +695,7 @@ trait JMDictSenseExt {
     fn can_be_candidate_for(&self, class: UposTag) -> bool;
 }
 
-impl JMDictSenseExt for jmdict::Sense {
+impl JMDictSenseExt for dict::Sense {
     fn can_be_candidate_for(&self, class: UposTag) -> bool {
         self.parts_of_speech().any(|jmdict_pos| {
             match class {
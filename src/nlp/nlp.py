@@ -7,23 +7,23 @@ VENV = os.environ.get("VENV")
 if VENV: sys.path.insert(0, VENV)
 
 import spacy
-import hashlib
-from pathlib import Path
 
 def load_model():
     global nlp, nlp_small
     nlp = spacy.load('ja_core_news_lg')
     nlp_small = spacy.load('ja_core_news_sm')
 
-CACHE = bool(os.environ.get("NLP_CACHE"))
-if CACHE:
-    Path(".nlp_cache/").mkdir(exist_ok=True)
 if not os.environ.get("NLP_LAZY"):
     load_model()
 else:
     nlp_small = None
     nlp = None
 
+def version():
+    models = ['ja_core_news_lg', 'ja_core_news_sm']
+    versions = [f"{m}-{spacy.util.get_package_version(m)}" for m in models]
+    return "/".join([f"spacy-{spacy.__version__}"] + versions)
+
 def tokenize(docs):
     global nlp_small
     if nlp_small is None:
@@ -46,5 +46,6 @@ def postprocess(doc):
     pos_tags = [token.pos_ for token in doc]
     lemmas = [token.lemma_ for token in doc]
     deps = [token.head.i for token in doc]
+    relations = [token.dep_ for token in doc]
 
-    return (words, pos_tags, lemmas, deps)
\ No newline at end of file
+    return (words, pos_tags, lemmas, deps, relations)
\ No newline at end of file
@@ -0,0 +1,159 @@
+//! Getting a whole library through parsing, hashing and the NLP engine without leaving cores
+//! idle. Work that's purely on the Rust side is spread across threads, and requests to the
+//! engine are queued up ahead so it never waits on us in between documents.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::IsTerminal;
+use std::num::NonZeroUsize;
+use std::thread;
+
+use crate::{
+    document::Document,
+    nlp::{Analysis, DocumentTokenization, Engine},
+};
+
+/// How many documents to have queued up with the engine at once.
+const IN_FLIGHT: usize = 4;
+/// Fewer documents than this go by quickly enough not to need a progress indicator.
+const PROGRESS_MIN: usize = 20;
+
+/// Applies `f` to every item, spread evenly across as many threads as there are cores. The
+/// results are in the same order as the items.
+pub fn parallel_map<T: Send, R: Send>(items: &mut [T], f: impl Fn(&mut T) -> R + Sync) -> Vec<R> {
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let chunk_size = items.len().div_ceil(threads).max(1);
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks_mut(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter_mut().map(f).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// A count of documents done, redrawn in place on stderr. Only shown on a terminal, and only
+/// when there are enough documents for it to be worth it.
+pub struct Progress {
+    label: &'static str,
+    done: usize,
+    total: usize,
+    visible: bool,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: usize) -> Self {
+        Self {
+            label,
+            done: 0,
+            total,
+            visible: total >= PROGRESS_MIN && std::io::stderr().is_terminal(),
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.done += 1;
+        if self.visible {
+            eprint!("\r{}: {}/{}", self.label, self.done, self.total);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.visible {
+            // clear the line, so whatever's printed next doesn't run into it
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+fn sentences(doc: &Document) -> Vec<String> {
+    doc.contents().map(String::from).collect()
+}
+
+/// Sends each document to the engine with `request`, keeping a few queued up, and hands the
+/// results to `finish` in order while the engine gets on with the next ones.
+async fn pipelined<T, F>(
+    docs: Vec<&mut Document>,
+    progress: &mut Progress,
+    request: impl Fn(&Document) -> F,
+    mut finish: impl FnMut(&mut Document, T) -> anyhow::Result<()>,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let mut docs = docs.into_iter();
+    let mut queue = VecDeque::with_capacity(IN_FLIGHT);
+    loop {
+        while queue.len() < IN_FLIGHT {
+            let Some(doc) = docs.next() else {
+                break;
+            };
+            let response = request(doc);
+            queue.push_back((doc, response));
+        }
+        let Some((doc, response)) = queue.pop_front() else {
+            return Ok(());
+        };
+        finish(doc, response.await?)?;
+        progress.tick();
+    }
+}
+
+/// Tokenizes the documents, calling `done` on each once it is, e.g. to cache the results.
+pub async fn tokenize(
+    engine: &Engine,
+    docs: Vec<&mut Document>,
+    mut done: impl FnMut(&Document) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut progress = Progress::new("Tokenizing", docs.len());
+    pipelined(
+        docs,
+        &mut progress,
+        |doc| engine.tokenize_batch(sentences(doc)),
+        |doc, tokenization: DocumentTokenization| {
+            doc.restore(Some(tokenization), None);
+            done(doc)
+        },
+    )
+    .await
+}
+
+/// Analyzes the documents, calling `done` on each once it is, e.g. to cache the results.
+pub async fn analyze(
+    engine: &Engine,
+    docs: Vec<&mut Document>,
+    mut done: impl FnMut(&Document) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut progress = Progress::new("Analyzing", docs.len());
+    pipelined(
+        docs,
+        &mut progress,
+        |doc| engine.morphological_analysis_batch(sentences(doc)),
+        |doc, analysis: Vec<Analysis>| {
+            doc.restore(None, Some(analysis));
+            done(doc)
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let mut items: Vec<u32> = (0..1000).collect();
+        let doubled = parallel_map(&mut items, |i| {
+            *i += 1;
+            *i * 2
+        });
+        assert_eq!(doubled, (1..=1000).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(items[999], 1000);
+    }
+}
@@ -0,0 +1,323 @@
+//! Dictionaries prebuilt into a file that's memory-mapped rather than read in, so looking a
+//! word up doesn't mean loading and indexing the whole dictionary first.
+//!
+//! A file is a table of records, each a serialized dictionary entry, and an FST from keys like
+//! readings to the records they're found in. Records are only deserialized when looked up.
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::Hasher;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use memmap2::Mmap;
+
+const MAGIC: &[u8; 8] = b"OMOIDX\0\0";
+/// Bump whenever the layout of the file changes.
+const PREBUILT_FORMAT_VERSION: u32 = 1;
+/// Magic, format version, padding, stamp, and the lengths of the four sections.
+const HEADER_LEN: usize = 8 + 4 + 4 + 8 + 4 * 8;
+
+/// Identifies the version of the file a table was built from, so a table built before it was
+/// replaced isn't used. Missing files get 0.
+pub fn stamp(source: Option<&Path>) -> u64 {
+    let Some(metadata) = source.and_then(|path| fs::metadata(path).ok()) else {
+        return 0;
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let mut hasher = fxhash::FxHasher64::default();
+    hasher.write_u64(metadata.len());
+    hasher.write_u128(modified);
+    hasher.finish()
+}
+
+/// Puts together a table. Keys can be added in any order, and the same key can lead to many
+/// records.
+#[derive(Default)]
+pub struct TableBuilder {
+    keys: BTreeMap<String, Vec<u32>>,
+    offsets: Vec<u64>,
+    records: Vec<u8>,
+}
+
+impl TableBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a record, returning its number.
+    pub fn push(&mut self, record: &[u8]) -> u32 {
+        self.offsets.push(self.records.len() as u64);
+        self.records.extend_from_slice(record);
+        self.offsets.len() as u32 - 1
+    }
+
+    /// Has `key` lead to the record numbered `record`, after any it already leads to.
+    pub fn key(&mut self, key: &str, record: u32) {
+        self.keys.entry(key.to_string()).or_default().push(record);
+    }
+
+    pub fn finish(mut self, stamp: u64) -> anyhow::Result<Vec<u8>> {
+        let mut postings: Vec<u8> = vec![];
+        let mut keys = fst::MapBuilder::memory();
+        for (key, records) in &self.keys {
+            // where the key's records start in the postings, and how many there are
+            let start = (postings.len() / 4) as u64;
+            keys.insert(key, start << 32 | records.len() as u64)?;
+            postings.extend(records.iter().flat_map(|record| record.to_le_bytes()));
+        }
+        let keys = keys.into_inner()?;
+        self.offsets.push(self.records.len() as u64);
+        let offsets: Vec<u8> = self
+            .offsets
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect();
+
+        let mut bytes = Vec::with_capacity(
+            HEADER_LEN + keys.len() + postings.len() + offsets.len() + self.records.len(),
+        );
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&PREBUILT_FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&stamp.to_le_bytes());
+        for section in [&keys, &postings, &offsets, &self.records] {
+            bytes.extend_from_slice(&(section.len() as u64).to_le_bytes());
+        }
+        for section in [keys, postings, offsets, self.records] {
+            bytes.extend_from_slice(&section);
+        }
+        Ok(bytes)
+    }
+}
+
+enum Bytes {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Bytes::Mapped(mmap) => mmap,
+            Bytes::Owned(bytes) => bytes,
+        }
+    }
+}
+
+/// The part of a table's bytes the FST is in.
+#[derive(Clone)]
+struct Section {
+    bytes: Arc<Bytes>,
+    range: Range<usize>,
+}
+
+impl AsRef<[u8]> for Section {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes.as_ref().as_ref()[self.range.clone()]
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// A table put together by `TableBuilder`.
+pub struct Table {
+    bytes: Arc<Bytes>,
+    keys: fst::Map<Section>,
+    postings: Range<usize>,
+    offsets: Range<usize>,
+    records: Range<usize>,
+    stamp: u64,
+}
+
+impl Table {
+    /// Maps the table at `path`, as long as it was built from the version of the source with
+    /// `stamp`.
+    pub fn open(path: &Path, stamp: u64) -> anyhow::Result<Self> {
+        let file =
+            fs::File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+        // Safety: the file is only ever replaced whole, by renaming a new one over it, so the
+        // mapped one doesn't change under us.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let table = Self::new(Bytes::Mapped(mmap))
+            .with_context(|| format!("'{}' is corrupt", path.display()))?;
+        anyhow::ensure!(
+            table.stamp == stamp,
+            "'{}' is out of date with the dictionary it was built from",
+            path.display()
+        );
+        Ok(table)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> anyhow::Result<Self> {
+        Self::new(Bytes::Owned(bytes))
+    }
+
+    fn new(bytes: Bytes) -> anyhow::Result<Self> {
+        let data = bytes.as_ref();
+        anyhow::ensure!(
+            data.len() >= HEADER_LEN && &data[..8] == MAGIC,
+            "Not a prebuilt dictionary"
+        );
+        anyhow::ensure!(
+            read_u32(data, 8) == PREBUILT_FORMAT_VERSION,
+            "Built by another version, build it again"
+        );
+        let stamp = read_u64(data, 16);
+        let mut sections = [0..0, 0..0, 0..0, 0..0];
+        let mut start = HEADER_LEN;
+        for (i, section) in sections.iter_mut().enumerate() {
+            let len = usize::try_from(read_u64(data, 24 + 8 * i))?;
+            let end = start.checked_add(len).context("Section too long")?;
+            *section = start..end;
+            start = end;
+        }
+        anyhow::ensure!(start == data.len(), "Truncated");
+        let [keys, postings, offsets, records] = sections;
+        anyhow::ensure!(
+            postings.len() % 4 == 0 && offsets.len() % 8 == 0 && !offsets.is_empty(),
+            "Misaligned"
+        );
+
+        let bytes = Arc::new(bytes);
+        let keys = fst::Map::new(Section {
+            bytes: bytes.clone(),
+            range: keys,
+        })?;
+        Ok(Self {
+            bytes,
+            keys,
+            postings,
+            offsets,
+            records,
+            stamp,
+        })
+    }
+
+    fn data(&self) -> &[u8] {
+        self.bytes.as_ref().as_ref()
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> usize {
+        self.offsets.len() / 8 - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The numbers of the records `key` leads to, in the order they were added.
+    pub fn get(&self, key: &str) -> impl Iterator<Item = u32> + '_ {
+        let (start, count) = self.keys.get(key).map_or((0, 0), |value| {
+            ((value >> 32) as usize, value as u32 as usize)
+        });
+        let postings = &self.data()[self.postings.clone()];
+        (start..start + count).map(|i| read_u32(postings, i * 4))
+    }
+
+    pub fn record(&self, i: u32) -> &[u8] {
+        let offsets = &self.data()[self.offsets.clone()];
+        let start = read_u64(offsets, i as usize * 8) as usize;
+        let end = read_u64(offsets, (i as usize + 1) * 8) as usize;
+        &self.data()[self.records.clone()][start..end]
+    }
+}
+
+/// A table's records, deserialized the first time they're asked for.
+pub struct Records<T> {
+    table: Table,
+    decoded: Vec<OnceLock<Box<T>>>,
+    decode: fn(&[u8]) -> anyhow::Result<T>,
+}
+
+impl<T> Records<T> {
+    pub fn new(table: Table, decode: fn(&[u8]) -> anyhow::Result<T>) -> Self {
+        Self {
+            decoded: (0..table.len()).map(|_| OnceLock::new()).collect(),
+            table,
+            decode,
+        }
+    }
+
+    /// For a table built in memory for records we already have, which are left out of it.
+    pub fn with_decoded(table: Table, records: Vec<T>) -> Self {
+        Self {
+            decoded: records
+                .into_iter()
+                .map(|record| OnceLock::from(Box::new(record)))
+                .collect(),
+            table,
+            decode: |_| anyhow::bail!("Record should've been decoded already"),
+        }
+    }
+
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    pub fn len(&self) -> usize {
+        self.decoded.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decoded.is_empty()
+    }
+
+    pub fn get(&self, i: u32) -> &T {
+        self.decoded[i as usize].get_or_init(|| {
+            let record =
+                (self.decode)(self.table.record(i)).expect("prebuilt dictionary record is corrupt");
+            Box::new(record)
+        })
+    }
+
+    /// The records `key` leads to.
+    pub fn lookup(&self, key: &str) -> impl Iterator<Item = &T> + '_ {
+        self.table.get(key).map(|i| self.get(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_round_trip() {
+        let mut builder = TableBuilder::new();
+        for (word, readings) in [
+            ("日本", ["にほん", "にっぽん"]),
+            ("二本", ["にほん", "にほん"]),
+        ] {
+            let record = builder.push(word.as_bytes());
+            builder.key(word, record);
+            for reading in readings {
+                builder.key(reading, record);
+            }
+        }
+        let table = Table::from_bytes(builder.finish(42).unwrap()).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.stamp, 42);
+
+        let records = Records::new(table, |bytes| Ok(String::from_utf8(bytes.to_vec())?));
+        let found: Vec<&String> = records.lookup("にほん").collect();
+        assert_eq!(found, ["日本", "二本", "二本"]);
+        assert_eq!(records.lookup("にっぽん").count(), 1);
+        assert_eq!(records.lookup("にっ").count(), 0);
+        assert_eq!(records.get(1), "二本");
+
+        assert!(Table::from_bytes(b"OMOIDX\0\0".to_vec()).is_err());
+    }
+}
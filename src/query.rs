@@ -0,0 +1,423 @@
+//! A small CQL-style language for finding sequences of tokens in the corpus, e.g.
+//! `[lemma="て"] [lemma="しまう"]` or `[pos=NOUN] [lemma="を"] [lemma="かける"]`.
+//!
+//! Each `[...]` matches a single token as split by the NLP engine, and may be followed by one of
+//! the quantifiers `?`, `*`, `+`, `{n}` or `{n,m}`. `[]` matches any token. Inside the brackets,
+//! attributes are compared with `=` or `!=` against a regex, which has to match the whole value.
+//! Comparisons can be combined with `&`, `|`, `!` and parentheses.
+//!
+//! Attributes are `surface` (or `word`), `lemma`, `pos` (the UPOS tag, e.g. `NOUN`), `role` (the
+//! role of the word the token is part of, e.g. `verb`) and `rel` (the dependency relation, e.g.
+//! `obj`). The last three are compared case-insensitively.
+use std::ops::Range;
+
+use anyhow::anyhow;
+use regex::Regex;
+
+use crate::index::IndexedUnit;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    Surface,
+    Lemma,
+    Pos,
+    Role,
+    Relation,
+}
+
+impl Attribute {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "surface" | "word" => Some(Self::Surface),
+            "lemma" => Some(Self::Lemma),
+            "pos" => Some(Self::Pos),
+            "role" => Some(Self::Role),
+            "rel" | "relation" | "dep" => Some(Self::Relation),
+            _ => None,
+        }
+    }
+
+    fn value<'a>(&self, unit: &'a IndexedUnit) -> &'a str {
+        match self {
+            Self::Surface => &unit.surface,
+            Self::Lemma => &unit.lemma,
+            Self::Pos => unit.pos.as_str(),
+            Self::Role => unit.role.as_str(),
+            Self::Relation => &unit.relation,
+        }
+    }
+
+    fn is_tag(&self) -> bool {
+        matches!(self, Self::Pos | Self::Role | Self::Relation)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    Any,
+    Compare {
+        attribute: Attribute,
+        negated: bool,
+        value: Regex,
+    },
+    Not(Box<Condition>),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+}
+
+impl Condition {
+    fn matches(&self, unit: &IndexedUnit) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Compare {
+                attribute,
+                negated,
+                value,
+            } => value.is_match(attribute.value(unit)) != *negated,
+            Self::Not(condition) => !condition.matches(unit),
+            Self::And(conditions) => conditions.iter().all(|c| c.matches(unit)),
+            Self::Or(conditions) => conditions.iter().any(|c| c.matches(unit)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TokenPattern {
+    condition: Condition,
+    min: usize,
+    max: usize,
+}
+
+/// A parsed token-sequence query.
+#[derive(Clone, Debug)]
+pub struct Query {
+    tokens: Vec<TokenPattern>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            chars: query.chars().collect(),
+            pos: 0,
+        };
+        let mut tokens = vec![];
+        parser.skip_whitespace();
+        while !parser.at_end() {
+            tokens.push(parser.token()?);
+            parser.skip_whitespace();
+        }
+        if tokens.is_empty() {
+            anyhow::bail!("Empty query");
+        }
+        Ok(Self { tokens })
+    }
+
+    /// Finds every non-overlapping match in a sequence of units, preferring the earliest and
+    /// then the longest ones. Returns the range of units each match covers.
+    pub fn find(&self, units: &[IndexedUnit]) -> Vec<Range<usize>> {
+        let mut found = vec![];
+        let mut start = 0;
+        while start < units.len() {
+            match self.match_at(0, units, start) {
+                Some(end) if end > start => {
+                    found.push(start..end);
+                    start = end;
+                }
+                _ => start += 1,
+            }
+        }
+        found
+    }
+
+    /// Matches the patterns from `token` onwards at `pos`, returning where the match ends.
+    fn match_at(&self, token: usize, units: &[IndexedUnit], pos: usize) -> Option<usize> {
+        let Some(pattern) = self.tokens.get(token) else {
+            return Some(pos);
+        };
+        let available = units[pos..]
+            .iter()
+            .take(pattern.max)
+            .take_while(|unit| pattern.condition.matches(unit))
+            .count();
+        if available < pattern.min {
+            return None;
+        }
+        // greedy, backing off until the rest of the query matches
+        (pattern.min..=available)
+            .rev()
+            .find_map(|n| self.match_at(token + 1, units, pos + n))
+    }
+}
+
+impl std::str::FromStr for Query {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        anyhow!("{message} at column {}", self.pos + 1)
+    }
+
+    fn expect(&mut self, c: char) -> anyhow::Result<()> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("Expected '{c}'")));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Consumes `c` if it's next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn token(&mut self) -> anyhow::Result<TokenPattern> {
+        self.expect('[')?;
+        let condition = match self.eat(']') {
+            true => Condition::Any,
+            false => {
+                let condition = self.disjunction()?;
+                self.expect(']')?;
+                condition
+            }
+        };
+        let (min, max) = match self.peek() {
+            Some('?') => (0, 1),
+            Some('*') => (0, usize::MAX),
+            Some('+') => (1, usize::MAX),
+            Some('{') => return self.repetition(condition),
+            _ => (1, 1),
+        };
+        if (min, max) != (1, 1) {
+            self.pos += 1; // the quantifier
+        }
+        Ok(TokenPattern {
+            condition,
+            min,
+            max,
+        })
+    }
+
+    /// `{n}`, `{n,}` or `{n,m}` following a token.
+    fn repetition(&mut self, condition: Condition) -> anyhow::Result<TokenPattern> {
+        self.expect('{')?;
+        let min = self.number()?;
+        let max = match self.eat(',') {
+            true if self.eat('}') => {
+                self.pos -= 1;
+                usize::MAX
+            }
+            true => self.number()?,
+            false => min,
+        };
+        if max < min {
+            return Err(self.error("Repetition maximum is below the minimum"));
+        }
+        self.expect('}')?;
+        Ok(TokenPattern {
+            condition,
+            min,
+            max,
+        })
+    }
+
+    fn number(&mut self) -> anyhow::Result<usize> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits.parse().map_err(|_| self.error("Expected a number"))
+    }
+
+    fn disjunction(&mut self) -> anyhow::Result<Condition> {
+        let mut conditions = vec![self.conjunction()?];
+        while self.eat('|') {
+            conditions.push(self.conjunction()?);
+        }
+        Ok(match conditions.len() {
+            1 => conditions.pop().unwrap(),
+            _ => Condition::Or(conditions),
+        })
+    }
+
+    fn conjunction(&mut self) -> anyhow::Result<Condition> {
+        let mut conditions = vec![self.unary()?];
+        while self.eat('&') {
+            conditions.push(self.unary()?);
+        }
+        Ok(match conditions.len() {
+            1 => conditions.pop().unwrap(),
+            _ => Condition::And(conditions),
+        })
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Condition> {
+        if self.eat('!') {
+            return Ok(Condition::Not(Box::new(self.unary()?)));
+        }
+        if self.eat('(') {
+            let condition = self.disjunction()?;
+            self.expect(')')?;
+            return Ok(condition);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> anyhow::Result<Condition> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            self.pos += 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().collect();
+        let attribute = match Attribute::from_name(&name) {
+            Some(attribute) => attribute,
+            None if name.is_empty() => return Err(self.error("Expected an attribute")),
+            None => {
+                self.pos = start;
+                return Err(self.error(&format!("Unknown attribute '{name}'")));
+            }
+        };
+
+        let negated = self.eat('!');
+        self.expect('=')?;
+        let value_start = self.pos;
+        let value = self.value()?;
+        let pattern = match attribute.is_tag() {
+            true => format!("(?i)^(?:{value})$"),
+            false => format!("^(?:{value})$"),
+        };
+        let value = Regex::new(&pattern).map_err(|e| {
+            self.pos = value_start;
+            self.error(&format!("Invalid pattern '{value}' ({e})"))
+        })?;
+
+        Ok(Condition::Compare {
+            attribute,
+            negated,
+            value,
+        })
+    }
+
+    /// A quoted string, or a bare word like `NOUN`.
+    fn value(&mut self) -> anyhow::Result<String> {
+        self.skip_whitespace();
+        let mut value = String::new();
+        if self.eat('"') {
+            loop {
+                match self.peek() {
+                    None => return Err(self.error("Unterminated string")),
+                    Some('"') => break,
+                    Some('\\') if self.chars.get(self.pos + 1) == Some(&'"') => {
+                        value.push('"');
+                        self.pos += 1;
+                    }
+                    Some(c) => value.push(c),
+                }
+                self.pos += 1;
+            }
+            self.pos += 1;
+        } else {
+            while let Some(c) = self.peek() {
+                if c.is_whitespace() || matches!(c, ']' | '&' | '|' | ')') {
+                    break;
+                }
+                value.push(c);
+                self.pos += 1;
+            }
+            if value.is_empty() {
+                return Err(self.error("Expected a value"));
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nlp::{UposTag, WordRole};
+
+    fn units(tokens: &[(&str, &str, UposTag)]) -> Vec<IndexedUnit> {
+        tokens
+            .iter()
+            .enumerate()
+            .map(|(i, &(surface, lemma, pos))| IndexedUnit {
+                surface: surface.into(),
+                lemma: lemma.into(),
+                pos,
+                role: WordRole::Other,
+                relation: String::new(),
+                word: i as u32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_token_sequences() {
+        let sentence = units(&[
+            ("電話", "電話", UposTag::Noun),
+            ("を", "を", UposTag::Adposition),
+            ("かけ", "かける", UposTag::Verb),
+            ("て", "て", UposTag::SubordinatingConjunction),
+            ("しまっ", "しまう", UposTag::Auxiliary),
+            ("た", "た", UposTag::Auxiliary),
+        ]);
+
+        let query = Query::parse(r#"[lemma="て"] [lemma="しまう"]"#).unwrap();
+        assert_eq!(query.find(&sentence), vec![3..5]);
+
+        let query = Query::parse(r#"[pos=NOUN] [lemma="を"] [lemma="かける"]"#).unwrap();
+        assert_eq!(query.find(&sentence), vec![0..3]);
+
+        let query = Query::parse(r#"[pos=verb] []{0,2} [pos="aux" & !lemma="た"]"#).unwrap();
+        assert_eq!(query.find(&sentence), vec![2..5]);
+
+        let query = Query::parse(r#"[lemma="か.*" | pos=NOUN]+"#).unwrap();
+        assert_eq!(query.find(&sentence), vec![0..1, 2..3]);
+
+        let query = Query::parse(r#"[lemma!="を"]"#).unwrap();
+        assert_eq!(query.find(&sentence).len(), 5);
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert!(Query::parse("").is_err());
+        assert!(Query::parse("[lemma=").is_err());
+        assert!(Query::parse(r#"[lemma="て"#).is_err());
+        let error = Query::parse(r#"[colour="赤"]"#).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown attribute 'colour' at column 2");
+        assert!(Query::parse("[]{3,1}").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Orders example sentences so the ones most useful for learning come first: short enough to
+//! take in at a glance, made of words the learner knows or will soon need, and drawn from as
+//! many different episodes and shows as possible.
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::RangeInclusive;
+
+use crate::{
+    concordance::Hit,
+    dict,
+    index::{CorpusIndex, IndexedChunk},
+    known::KnownWords,
+    metadata::EpisodeMetadata,
+    nlp::UposTag,
+};
+
+/// Lengths, in characters, of sentences that make for good examples. Shorter ones tend to lack
+/// context, longer ones bury the word being looked up.
+const IDEAL_LENGTH: RangeInclusive<usize> = 6..=24;
+/// How much an example's score is scaled down by for each better one from the same episode...
+const SAME_DOCUMENT_PENALTY: f32 = 0.5;
+/// ...and from the same show.
+const SAME_SHOW_PENALTY: f32 = 0.8;
+
+/// An example to be ranked, along with what we need to know about it.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub hit: Hit,
+    /// How good an example it is on its own, from 0 to 1.
+    pub score: f32,
+    pub show: Option<String>,
+    /// The text with everything but letters and digits removed, so lines that differ only in
+    /// punctuation or spacing count as duplicates.
+    pub key: String,
+}
+
+fn length_score(length: usize) -> f32 {
+    match length {
+        0 => 0.0,
+        n if n < *IDEAL_LENGTH.start() => n as f32 / *IDEAL_LENGTH.start() as f32,
+        n if n > *IDEAL_LENGTH.end() => *IDEAL_LENGTH.end() as f32 / n as f32,
+        _ => 1.0,
+    }
+}
+
+fn dedup_key(text: &str) -> String {
+    text.chars().filter(|c| c.is_alphanumeric()).collect()
+}
+
+/// Proportion of the words in the chunk which are either known or common.
+fn familiarity(chunk: &IndexedChunk, known: Option<&KnownWords>) -> f32 {
+    let mut is_punctuation = vec![true; chunk.words.len()];
+    for unit in &chunk.units {
+        if !matches!(unit.pos, UposTag::Punctuation | UposTag::Symbol) {
+            is_punctuation[unit.word as usize] = false;
+        }
+    }
+
+    let words: Vec<_> = chunk
+        .words
+        .iter()
+        .zip(is_punctuation)
+        .filter(|(_, is_punctuation)| !is_punctuation)
+        .map(|(word, _)| word)
+        .collect();
+    if words.is_empty() {
+        return 0.0;
+    }
+    let familiar = words
+        .iter()
+        .filter(|word| {
+            known.is_some_and(|known| known.contains(&word.lemma) || known.contains(&word.surface))
+                || dict::is_common(&word.lemma)
+        })
+        .count();
+    familiar as f32 / words.len() as f32
+}
+
+impl Candidate {
+    pub fn new(
+        index: &CorpusIndex,
+        hit: Hit,
+        show: Option<String>,
+        known: Option<&KnownWords>,
+    ) -> Self {
+        let chunk = &index.document(hit.doc).chunks[hit.chunk as usize];
+        let key = dedup_key(&chunk.text);
+        let score = 0.4 * length_score(key.chars().count()) + 0.6 * familiarity(chunk, known);
+        Self {
+            hit,
+            score,
+            show,
+            key,
+        }
+    }
+}
+
+#[derive(PartialEq)]
+struct Scored {
+    score: f32,
+    i: usize,
+}
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // ties go to whichever came first
+        self.score
+            .total_cmp(&other.score)
+            .then(other.i.cmp(&self.i))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders the candidates best first, dropping any with the same text as a better one.
+/// Each pick makes the remaining examples from the same episode and show less attractive, so
+/// the top results aren't all from one place.
+pub fn select(candidates: Vec<Candidate>) -> Vec<Hit> {
+    let mut heap: BinaryHeap<Scored> = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| Scored {
+            score: candidate.score,
+            i,
+        })
+        .collect();
+    let mut picked_keys = HashSet::new();
+    let mut picked_per_doc: HashMap<u32, i32> = HashMap::new();
+    let mut picked_per_show: HashMap<&str, i32> = HashMap::new();
+    let mut selected = vec![];
+
+    // penalties only ever lower scores, so a candidate that is still on top once its score is
+    // brought up to date is the best one left
+    while let Some(Scored { score, i }) = heap.pop() {
+        let candidate = &candidates[i];
+        if picked_keys.contains(candidate.key.as_str()) {
+            continue;
+        }
+        let same_doc = picked_per_doc.get(&candidate.hit.doc).copied().unwrap_or(0);
+        let same_show = candidate
+            .show
+            .as_deref()
+            .and_then(|show| picked_per_show.get(show))
+            .copied()
+            .unwrap_or(0);
+        let current = candidate.score
+            * SAME_DOCUMENT_PENALTY.powi(same_doc)
+            * SAME_SHOW_PENALTY.powi(same_show);
+        if current < score {
+            heap.push(Scored { score: current, i });
+            continue;
+        }
+
+        picked_keys.insert(candidate.key.as_str());
+        *picked_per_doc.entry(candidate.hit.doc).or_default() += 1;
+        if let Some(show) = candidate.show.as_deref() {
+            *picked_per_show.entry(show).or_default() += 1;
+        }
+        selected.push(candidate.hit.clone());
+    }
+
+    selected
+}
+
+/// Ranks hits from the index, see [`select`].
+pub fn rank(index: &CorpusIndex, hits: Vec<Hit>, known: Option<&KnownWords>) -> Vec<Hit> {
+    let mut shows: HashMap<u32, Option<String>> = HashMap::new();
+    let candidates = hits
+        .into_iter()
+        .map(|hit| {
+            let show = shows
+                .entry(hit.doc)
+                .or_insert_with(|| {
+                    index
+                        .document(hit.doc)
+                        .source
+                        .as_deref()
+                        .and_then(|path| EpisodeMetadata::from_path(path).show)
+                })
+                .clone();
+            Candidate::new(index, hit, show, known)
+        })
+        .collect();
+    select(candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(doc: u32, chunk: u32, score: f32, show: &str, text: &str) -> Candidate {
+        Candidate {
+            hit: Hit {
+                doc,
+                chunk,
+                span: 0..0,
+            },
+            score,
+            show: Some(show.into()),
+            key: dedup_key(text),
+        }
+    }
+
+    #[test]
+    fn prefers_sentences_of_reasonable_length() {
+        assert!(length_score(3) < length_score(10));
+        assert!(length_score(60) < length_score(10));
+        assert_eq!(length_score(0), 0.0);
+    }
+
+    #[test]
+    fn spreads_examples_and_drops_duplicates() {
+        let ranked = select(vec![
+            candidate(0, 0, 0.9, "A", "ボールを取って"),
+            candidate(0, 1, 0.85, "A", "手紙を取った"),
+            candidate(1, 0, 0.9, "A", "ボールを、取って！"),
+            candidate(2, 0, 0.6, "B", "赤い紙を取る"),
+        ]);
+        let order: Vec<_> = ranked.iter().map(|hit| (hit.doc, hit.chunk)).collect();
+        assert_eq!(order, vec![(0, 0), (2, 0), (0, 1)]);
+    }
+}
@@ -0,0 +1,289 @@
+//! Lines that come back episode after episode of a show, like the lyrics of the opening and
+//! ending songs, recaps and "next time" previews. They'd otherwise drown out everything else in
+//! word counts and fill search results with the same line over and over.
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::{
+    args::RepeatArgs,
+    document::{Document, DocumentChunk},
+    index::CorpusIndex,
+    metadata::EpisodeMetadata,
+};
+
+/// How finely the position of a line in an episode is told apart. Lines count as being in the
+/// same place if they're at most one part apart, which allows for cold opens of varying length.
+const POSITION_BUCKETS: usize = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RepeatConfig {
+    /// Proportion of a show's episodes a line has to turn up in, in roughly the same place.
+    pub min_fraction: f32,
+    /// Fewest episodes a line has to turn up in, so two episodes can't make a pattern.
+    pub min_documents: usize,
+    /// Shortest line, in letters and digits, that counts. Short replies like "うん" are in
+    /// every episode anyway.
+    pub min_length: usize,
+}
+
+impl Default for RepeatConfig {
+    fn default() -> Self {
+        Self {
+            min_fraction: 0.5,
+            min_documents: 3,
+            min_length: 5,
+        }
+    }
+}
+
+impl RepeatConfig {
+    pub fn from_args(args: &RepeatArgs) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            args.repeated_in > 0.0 && args.repeated_in <= 1.0,
+            "--repeated-in has to be more than 0 and at most 1"
+        );
+        Ok(Self {
+            min_fraction: args.repeated_in,
+            ..Default::default()
+        })
+    }
+}
+
+/// A cue, as far as telling whether it's repeated goes.
+#[derive(Clone, Copy, Debug)]
+pub struct Cue<'a> {
+    pub text: &'a str,
+    pub start: Option<Duration>,
+}
+
+/// Lines are compared ignoring punctuation and spacing, which varies between releases.
+fn normalize(text: &str) -> String {
+    text.chars().filter(|c| c.is_alphanumeric()).collect()
+}
+
+/// Where each cue is in the episode, going by its timing if every cue has one, or else by
+/// where it is in the file.
+fn positions(cues: &[Cue]) -> Vec<usize> {
+    let bucket =
+        |position: f64| ((position * POSITION_BUCKETS as f64) as usize).min(POSITION_BUCKETS - 1);
+    let last_start = cues.iter().map(|cue| cue.start).max().flatten();
+    match last_start {
+        Some(last) if !last.is_zero() && cues.iter().all(|cue| cue.start.is_some()) => cues
+            .iter()
+            .map(|cue| bucket(cue.start.unwrap().as_secs_f64() / last.as_secs_f64()))
+            .collect(),
+        _ => {
+            let last = cues.len().saturating_sub(1).max(1) as f64;
+            (0..cues.len()).map(|i| bucket(i as f64 / last)).collect()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Series {
+    documents: usize,
+    /// Where each line was seen, as document, cue and position.
+    lines: HashMap<String, Vec<(u32, u32, usize)>>,
+}
+
+/// Gathers the cues of every document before working out which lines repeat.
+pub struct RepeatDetector {
+    config: RepeatConfig,
+    series: HashMap<Option<String>, Series>,
+}
+
+impl RepeatDetector {
+    pub fn new(config: RepeatConfig) -> Self {
+        Self {
+            config,
+            series: HashMap::new(),
+        }
+    }
+
+    /// Adds the cues of the document numbered `doc`, from the show `series` if we know it.
+    pub fn add(&mut self, doc: u32, series: Option<&str>, cues: &[Cue]) {
+        let series = self.series.entry(series.map(String::from)).or_default();
+        series.documents += 1;
+        for (i, (cue, position)) in cues.iter().zip(positions(cues)).enumerate() {
+            let line = normalize(cue.text);
+            if line.chars().count() >= self.config.min_length {
+                series
+                    .lines
+                    .entry(line)
+                    .or_default()
+                    .push((doc, i as u32, position));
+            }
+        }
+    }
+
+    pub fn finish(self) -> RepeatedLines {
+        let mut cues = HashSet::new();
+        for series in self.series.into_values() {
+            let needed = ((self.config.min_fraction * series.documents as f32).ceil() as usize)
+                .max(self.config.min_documents);
+            for seen in series.lines.into_values() {
+                // the positions the line is common at, give or take one
+                let common: Vec<usize> = (0..POSITION_BUCKETS)
+                    .filter(|&bucket| {
+                        let docs: HashSet<u32> = seen
+                            .iter()
+                            .filter(|(_, _, position)| position.abs_diff(bucket) <= 1)
+                            .map(|&(doc, _, _)| doc)
+                            .collect();
+                        docs.len() >= needed
+                    })
+                    .collect();
+                // the same line said somewhere else entirely is left alone
+                cues.extend(
+                    seen.iter()
+                        .filter(|(_, _, position)| {
+                            common.iter().any(|bucket| position.abs_diff(*bucket) <= 1)
+                        })
+                        .map(|&(doc, cue, _)| (doc, cue)),
+                );
+            }
+        }
+        RepeatedLines { cues }
+    }
+
+    /// Finds the repeated lines among documents numbered by their place in `docs`.
+    pub fn find_in_documents<'a>(
+        docs: impl IntoIterator<Item = &'a Document>,
+        config: RepeatConfig,
+    ) -> RepeatedLines {
+        let mut detector = Self::new(config);
+        for (doc_i, doc) in docs.into_iter().enumerate() {
+            let cues: Vec<Cue> = doc
+                .chunks()
+                .iter()
+                .map(|chunk| Cue {
+                    text: chunk.contents(),
+                    start: match chunk {
+                        DocumentChunk::Subs(sub) => Some(sub.start),
+                        DocumentChunk::Plaintext(_) => None,
+                    },
+                })
+                .collect();
+            detector.add(doc_i as u32, doc.metadata().show.as_deref(), &cues);
+        }
+        detector.finish()
+    }
+
+    /// Finds the repeated lines among the documents in the index that `include` says to, e.g.
+    /// the ones in the corpus being searched.
+    pub fn find_in_index(
+        index: &CorpusIndex,
+        include: impl Fn(u64) -> bool,
+        config: RepeatConfig,
+    ) -> RepeatedLines {
+        let mut detector = Self::new(config);
+        for (doc_i, doc) in index.documents().iter().enumerate() {
+            if !include(doc.content_hash) {
+                continue;
+            }
+            let show = doc
+                .source
+                .as_deref()
+                .and_then(|path| EpisodeMetadata::from_path(path).show);
+            let cues: Vec<Cue> = doc
+                .chunks
+                .iter()
+                .map(|chunk| Cue {
+                    text: &chunk.text,
+                    start: chunk.start,
+                })
+                .collect();
+            detector.add(doc_i as u32, show.as_deref(), &cues);
+        }
+        detector.finish()
+    }
+}
+
+/// The cues found to be repeated, by document and cue number.
+#[derive(Clone, Debug, Default)]
+pub struct RepeatedLines {
+    cues: HashSet<(u32, u32)>,
+}
+
+impl RepeatedLines {
+    pub fn contains(&self, doc: u32, cue: u32) -> bool {
+        self.cues.contains(&(doc, cue))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cues.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cues.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn episode(lines: &[&'static str]) -> Vec<Cue<'static>> {
+        lines
+            .iter()
+            .enumerate()
+            .map(|(i, text)| Cue {
+                text,
+                start: Some(Duration::from_secs(60 * i as u64)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_lines_repeated_in_the_same_place() {
+        let opening = "夢の続きを探しに行こう";
+        let preview = "次回、運命の再会！";
+        let episodes = [
+            episode(&[
+                opening,
+                "今日は学校に行かない",
+                "うん",
+                "それは困りましたね",
+                preview,
+            ]),
+            episode(&[
+                opening,
+                "先生に聞いてみようか",
+                "うん",
+                "夢の続きを探しに行こう",
+                preview,
+            ]),
+            episode(&[
+                "もう朝ごはんの時間だよ",
+                opening,
+                "うん",
+                "手紙を書いてみました",
+                preview,
+            ]),
+            episode(&[
+                opening,
+                "ボールを取ってください",
+                "うん",
+                "また明日会いましょう",
+                "おやすみなさい",
+            ]),
+        ];
+
+        let mut detector = RepeatDetector::new(RepeatConfig::default());
+        for (i, cues) in episodes.iter().enumerate() {
+            detector.add(i as u32, Some("Show"), cues);
+        }
+        let repeated = detector.finish();
+
+        // the opening, even after a cold open, but not when quoted later on
+        assert!(repeated.contains(0, 0));
+        assert!(repeated.contains(2, 1));
+        assert!(!repeated.contains(1, 3));
+        // the preview
+        assert!(repeated.contains(0, 4));
+        assert!(repeated.contains(2, 4));
+        // too short to tell
+        assert!(!repeated.contains(0, 2));
+        assert_eq!(repeated.len(), 7);
+    }
+}
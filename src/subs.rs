@@ -1,5 +1,6 @@
 //! Handle parsing subtitle files and getting the stuff we want from them.
 use anyhow::Context;
+use serde::{Deserialize, Serialize};
 use srtlib::{Subtitles, Timestamp};
 use std::fs;
 use std::path::Path;
@@ -14,6 +15,46 @@ pub struct SubtitleChunk {
     pub content: String,
 }
 
+/// The subtitle file formats we know how to read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+pub enum SubtitleFormat {
+    Srt,
+    #[value(name = "vtt")]
+    WebVtt,
+    Ass,
+}
+
+impl SubtitleFormat {
+    /// Guess the format from the file extension alone.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "srt" => Some(Self::Srt),
+            "vtt" => Some(Self::WebVtt),
+            "ass" | "ssa" => Some(Self::Ass),
+            _ => None,
+        }
+    }
+
+    /// Guess the format by looking at the start of the file contents.
+    pub fn sniff(content: &str) -> Option<Self> {
+        let content = content.trim_start_matches('\u{feff}').trim_start();
+        if content.starts_with("WEBVTT") {
+            Some(Self::WebVtt)
+        } else if content.starts_with("[Script Info]") {
+            Some(Self::Ass)
+        } else if content
+            .lines()
+            .take(4)
+            .any(|line| line.contains("-->") && line.contains(','))
+        {
+            Some(Self::Srt)
+        } else {
+            None
+        }
+    }
+}
+
 fn timestamp_to_duration(t: &Timestamp) -> Duration {
     let (hours, minutes, seconds, milliseconds) = t.get();
     let milliseconds =
@@ -24,7 +65,10 @@ fn timestamp_to_duration(t: &Timestamp) -> Duration {
 pub fn parse_subtitle_file(path: impl AsRef<Path>) -> anyhow::Result<Vec<SubtitleChunk>> {
     let string = fs::read_to_string(path.as_ref())
         .with_context(|| format!("Failed to file at '{}',", path.as_ref().display()))?;
-    parse_subtitle_content(string)
+    let format = SubtitleFormat::from_extension(path.as_ref())
+        .or_else(|| SubtitleFormat::sniff(&string))
+        .unwrap_or(SubtitleFormat::Srt);
+    parse_subtitle_content_as(string, format)
 }
 
 pub fn parse_subtitle_content(content: String) -> anyhow::Result<Vec<SubtitleChunk>> {
@@ -41,3 +85,302 @@ pub fn parse_subtitle_content(content: String) -> anyhow::Result<Vec<SubtitleChu
         })
         .collect())
 }
+
+pub fn parse_subtitle_content_as(
+    content: String,
+    format: SubtitleFormat,
+) -> anyhow::Result<Vec<SubtitleChunk>> {
+    match format {
+        SubtitleFormat::Srt => parse_subtitle_content(content),
+        _ => parse_subtitle_content_lenient(&content, format).into_strict(),
+    }
+}
+
+/// Something wrong with part of a subtitle file, which was skipped over.
+#[derive(Clone, Debug)]
+pub struct ParseIssue {
+    /// 1-based line number where the problem was found.
+    pub line: usize,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ParseIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+/// Whatever could be salvaged from a subtitle file, along with what went wrong in the rest of it.
+#[derive(Clone, Debug, Default)]
+pub struct ParsedSubtitles {
+    pub chunks: Vec<SubtitleChunk>,
+    pub issues: Vec<ParseIssue>,
+}
+
+impl ParsedSubtitles {
+    fn issue(&mut self, line: usize, reason: impl Into<String>) {
+        self.issues.push(ParseIssue {
+            line,
+            reason: reason.into(),
+        });
+    }
+
+    /// Fails on the first issue found, for callers that don't want partial results.
+    pub fn into_strict(self) -> anyhow::Result<Vec<SubtitleChunk>> {
+        match self.issues.into_iter().next() {
+            Some(issue) => anyhow::bail!("{issue}"),
+            None => Ok(self.chunks),
+        }
+    }
+}
+
+/// Parses as much of the file as possible, skipping over malformed cues instead of giving up.
+pub fn parse_subtitle_content_lenient(content: &str, format: SubtitleFormat) -> ParsedSubtitles {
+    let content = content.trim_start_matches('\u{feff}');
+    match format {
+        SubtitleFormat::Srt => parse_srt_lenient(content),
+        SubtitleFormat::WebVtt => parse_webvtt_content(content),
+        SubtitleFormat::Ass => parse_ass_content(content),
+    }
+}
+
+/// Parses `hh:mm:ss.mmm`, `mm:ss.mmm` and the centisecond `h:mm:ss.cc` used by ASS.
+fn parse_clock(s: &str) -> Option<Duration> {
+    let (clock, fraction) = s.trim().split_once(['.', ','])?;
+    let mut secs = 0u64;
+    for part in clock.split(':') {
+        secs = secs * 60 + part.parse::<u64>().ok()?;
+    }
+    let millis = match fraction.len() {
+        2 => fraction.parse::<u64>().ok()? * 10,
+        3 => fraction.parse::<u64>().ok()?,
+        _ => return None,
+    };
+    Some(Duration::from_millis(secs * 1000 + millis))
+}
+
+/// Parses a `start --> end` cue timing line, ignoring any positioning or cue settings after it.
+fn parse_cue_timing(line: &str) -> Option<(Duration, Duration)> {
+    let (start, rest) = line.split_once("-->")?;
+    let end = rest.split_whitespace().next()?;
+    Some((parse_clock(start)?, parse_clock(end)?))
+}
+
+fn parse_srt_lenient(content: &str) -> ParsedSubtitles {
+    let mut parsed = ParsedSubtitles::default();
+    let mut cue: Option<(Duration, Duration, Vec<&str>)> = None;
+    // set after a bad timing line, so we drop the text that belongs to it
+    let mut skipping = false;
+    let mut lines = content.lines().enumerate().peekable();
+
+    fn flush(cue: &mut Option<(Duration, Duration, Vec<&str>)>, parsed: &mut ParsedSubtitles) {
+        if let Some((start, end, text)) = cue.take() {
+            if !text.is_empty() {
+                parsed.chunks.push(SubtitleChunk {
+                    start,
+                    end,
+                    content: text.join("\n"),
+                });
+            }
+        }
+    }
+
+    while let Some((line_no, line)) = lines.next() {
+        let line_no = line_no + 1;
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            flush(&mut cue, &mut parsed);
+            skipping = false;
+            continue;
+        }
+
+        // a cue index, if it's followed by a timing line. files with missing blank lines between
+        // cues rely on this to split them
+        let next_is_timing = lines.peek().is_some_and(|(_, next)| next.contains("-->"));
+        if trimmed.parse::<u32>().is_ok() && next_is_timing {
+            flush(&mut cue, &mut parsed);
+            skipping = false;
+            continue;
+        }
+
+        if trimmed.contains("-->") {
+            flush(&mut cue, &mut parsed);
+            match parse_cue_timing(trimmed) {
+                Some((start, end)) => {
+                    cue = Some((start, end, vec![]));
+                    skipping = false;
+                }
+                None => {
+                    parsed.issue(line_no, format!("invalid cue timing '{trimmed}'"));
+                    skipping = true;
+                }
+            }
+            continue;
+        }
+
+        match cue.as_mut() {
+            Some((_, _, text)) => text.push(trimmed),
+            None if skipping => {}
+            None => {
+                parsed.issue(line_no, "text outside of a cue");
+                skipping = true;
+            }
+        }
+    }
+    flush(&mut cue, &mut parsed);
+
+    parsed
+}
+
+fn parse_webvtt_content(content: &str) -> ParsedSubtitles {
+    let mut parsed = ParsedSubtitles::default();
+    if !content.starts_with("WEBVTT") {
+        parsed.issue(1, "missing WEBVTT header");
+    }
+
+    let mut lines = content.lines().enumerate();
+    while let Some((line_no, line)) = lines.next() {
+        if !line.contains("-->") {
+            continue;
+        }
+        let timing = parse_cue_timing(line);
+        let text: Vec<&str> = lines
+            .by_ref()
+            .map(|(_, line)| line)
+            .take_while(|line| !line.trim().is_empty())
+            .collect();
+        let Some((start, end)) = timing else {
+            parsed.issue(line_no + 1, format!("invalid cue timing '{}'", line.trim()));
+            continue;
+        };
+        if !text.is_empty() {
+            parsed.chunks.push(SubtitleChunk {
+                start,
+                end,
+                content: text.join("\n"),
+            });
+        }
+    }
+    parsed
+}
+
+fn parse_ass_content(content: &str) -> ParsedSubtitles {
+    let mut parsed = ParsedSubtitles::default();
+    let mut format: Option<Vec<String>> = None;
+    let mut in_events = false;
+
+    for (line_no, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[Events]");
+            continue;
+        }
+        if !in_events {
+            continue;
+        }
+        if let Some(fields) = line.strip_prefix("Format:") {
+            format = Some(fields.split(',').map(|f| f.trim().to_lowercase()).collect());
+        } else if let Some(fields) = line.strip_prefix("Dialogue:") {
+            let Some(format) = format.as_ref() else {
+                parsed.issue(line_no + 1, "dialogue before format line");
+                continue;
+            };
+            // the text field is last and may itself contain commas
+            let fields: Vec<&str> = fields.splitn(format.len(), ',').collect();
+            let field = |name: &str| {
+                format
+                    .iter()
+                    .position(|f| f == name)
+                    .and_then(|i| fields.get(i))
+            };
+            let (Some(start), Some(end), Some(text)) = (
+                field("start").and_then(|s| parse_clock(s)),
+                field("end").and_then(|s| parse_clock(s)),
+                field("text"),
+            ) else {
+                parsed.issue(line_no + 1, "malformed dialogue");
+                continue;
+            };
+            let text = strip_ass_markup(text);
+            if !text.is_empty() {
+                parsed.chunks.push(SubtitleChunk {
+                    start,
+                    end,
+                    content: text,
+                });
+            }
+        }
+    }
+    parsed
+}
+
+/// Drop `{\override}` blocks and turn ASS line breaks into real ones.
+fn strip_ass_markup(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut depth = 0usize;
+    for c in text.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            c if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out.replace("\\N", "\n")
+        .replace("\\n", "\n")
+        .replace("\\h", " ")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_formats() {
+        assert_eq!(
+            SubtitleFormat::sniff("\u{feff}WEBVTT\n\n"),
+            Some(SubtitleFormat::WebVtt)
+        );
+        assert_eq!(
+            SubtitleFormat::sniff("[Script Info]\nTitle: x\n"),
+            Some(SubtitleFormat::Ass)
+        );
+        assert_eq!(
+            SubtitleFormat::sniff("1\n00:00:01,000 --> 00:00:02,000\nこんにちは\n"),
+            Some(SubtitleFormat::Srt)
+        );
+        assert_eq!(SubtitleFormat::sniff("just some notes"), None);
+    }
+
+    #[test]
+    fn parses_webvtt() {
+        let vtt = "WEBVTT\n\n1\n00:01.500 --> 00:03.000 align:start\nおはよう\nございます\n\n00:01:04.000 --> 00:01:05.250\nまた\n";
+        let chunks = parse_webvtt_content(vtt).chunks;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].start, Duration::from_millis(1500));
+        assert_eq!(chunks[0].content, "おはよう\nございます");
+        assert_eq!(chunks[1].end, Duration::from_millis(65250));
+    }
+
+    #[test]
+    fn parses_ass() {
+        let ass = "[Script Info]\nTitle: x\n\n[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\nDialogue: 0,0:00:02.50,0:00:04.00,Default,,0,0,0,,{\\i1}待って、\\Nください{\\i0}\n";
+        let chunks = parse_ass_content(ass).chunks;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].start, Duration::from_millis(2500));
+        assert_eq!(chunks[0].content, "待って、\nください");
+    }
+
+    #[test]
+    fn recovers_from_malformed_srt() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,000\nはい\n\n2\n00:00:0x,000 --> 00:00:04,000\n壊れた\n\n3\n00:00:05,000 --> 00:00:06,000\nいいえ\n4\n00:00:07,000 --> 00:00:08,000\nまた\n";
+        let parsed = parse_srt_lenient(srt);
+        let contents: Vec<&str> = parsed.chunks.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(contents, ["はい", "いいえ", "また"]);
+        assert_eq!(parsed.issues.len(), 1);
+        assert_eq!(parsed.issues[0].line, 6);
+    }
+}